@@ -7,14 +7,16 @@
 //!   control-u -- kill line
 //!   control-d -- end of file
 //!   control-p -- print process list
+//!   control-t -- print memory usage
 
 use crate::{
-    file::DEV_SW,
-    proc::PROCS,
+    kalloc,
+    proc::{CPUS, PROCS},
     spinlock::SpinMutex,
-    uart::{self, uart_putc_sync},
+    uaccess::UserSlice,
+    uart::{uart_putc_sync, UART},
 };
-use core::{fmt, ptr};
+use core::ptr;
 
 pub static CONS: SpinMutex<Console> = SpinMutex::new("cons", Console::default());
 
@@ -56,6 +58,10 @@ pub fn console_intr(c: u8) {
             // print process list
             PROCS.proc_dump();
         }
+        c if c == ctrl(b'T') => {
+            // print memory usage
+            kalloc::mem_dump();
+        }
         c if c == ctrl(b'U') => {
             // erase line
             while cons.e != cons.w && cons.buf[(cons.e - 1) % INPUT_BUF] != b'\n' {
@@ -107,16 +113,74 @@ pub fn cons_putc(c: u8) {
 }
 
 /// user write()s to the console go here.
-pub(crate) fn console_write(user_src: i32, src: u64, n: i32) -> i32 {
-    // TODO: implement
-    0
+pub(crate) fn console_write(src: UserSlice) -> i32 {
+    let mut buf = [0u8; 32];
+    let mut i = 0;
+
+    while i < src.len() {
+        let n = buf.len().min(src.len() - i);
+        if src.skip(i).read(&mut buf[..n]).is_err() {
+            break;
+        }
+        for &c in &buf[..n] {
+            UART.uart_putc(c);
+        }
+        i += n;
+    }
+
+    i as i32
 }
 
 /// user read()s from the console go here.
 /// copy (up to) a whole input line to dst.
-/// user_dist indicates whether dst is a user
-/// or kernel address.
-pub(crate) fn console_read(user_dst: i32, dst: u64, n: i32) -> i32 {
-    // TODO: implement
-    0
+pub(crate) fn console_read(dst: UserSlice) -> i32 {
+    let target = dst.len();
+    let mut n = 0;
+
+    let mut cons = CONS.lock();
+    while n < target {
+        // wait until interrupt handler has put some
+        // input into cons.buffer.
+        loop {
+            if cons.r != cons.w {
+                break;
+            }
+            if CPUS.myproc().map_or(false, |p| p.killed()) {
+                return -1;
+            }
+            PROCS.sleep(ptr::addr_of!(cons.r) as usize, &cons);
+        }
+
+        let c = cons.buf[cons.r % INPUT_BUF];
+        cons.r += 1;
+
+        if c == ctrl(b'D') {
+            // end-of-file
+            if n > 0 {
+                // Save ^D for next time, to make sure
+                // caller gets a 0-byte result.
+                cons.r -= 1;
+            }
+            break;
+        }
+
+        // copy the input byte to the user-space buffer. that may
+        // sleep to swap a page in, so not holding cons.
+        drop(cons);
+        let copied = dst.skip(n).write(&[c]);
+        cons = CONS.lock();
+        if copied.is_err() {
+            break;
+        }
+
+        n += 1;
+
+        if c == b'\n' {
+            // a whole line has arrived, return to
+            // the user-level read().
+            break;
+        }
+    }
+
+    n as i32
 }
@@ -0,0 +1,68 @@
+//! Errors returned by the fallible (try_) kernel APIs.
+//!
+//! Each subsystem has its own error type saying exactly what went
+//! wrong; KernelError is what they have in common, for callers that
+//! only need to know whether to give up, retry or blame the caller.
+
+use crate::{
+    mmap::MmapError,
+    shm::ShmError,
+    vm::{CopyError, FaultError, MapToError},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KernelError {
+    /// No physical memory or kernel heap left, even after swapping.
+    OutOfMemory,
+    /// A user address is not mapped, or not for this access.
+    BadAddress,
+    /// Bad argument, or a limit such as a table size was hit.
+    InvalidArgument,
+}
+
+impl From<MapToError> for KernelError {
+    fn from(err: MapToError) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => KernelError::OutOfMemory,
+        }
+    }
+}
+
+impl From<FaultError> for KernelError {
+    fn from(err: FaultError) -> Self {
+        match err {
+            FaultError::BadAddress => KernelError::BadAddress,
+            FaultError::OutOfMemory => KernelError::OutOfMemory,
+        }
+    }
+}
+
+impl From<CopyError> for KernelError {
+    fn from(err: CopyError) -> Self {
+        match err {
+            CopyError::BadAddress => KernelError::BadAddress,
+            CopyError::TooLong => KernelError::InvalidArgument,
+            CopyError::OutOfMemory => KernelError::OutOfMemory,
+        }
+    }
+}
+
+impl From<MmapError> for KernelError {
+    fn from(err: MmapError) -> Self {
+        match err {
+            MmapError::InvalidArgument | MmapError::NoSpace | MmapError::Unsupported => {
+                KernelError::InvalidArgument
+            }
+            MmapError::OutOfMemory => KernelError::OutOfMemory,
+        }
+    }
+}
+
+impl From<ShmError> for KernelError {
+    fn from(err: ShmError) -> Self {
+        match err {
+            ShmError::InvalidArgument | ShmError::NoSpace => KernelError::InvalidArgument,
+            ShmError::OutOfMemory => KernelError::OutOfMemory,
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use crate::{
     console::{console_read, console_write},
     param::NDEV,
+    uaccess::UserSlice,
 };
 
 pub static DEV_SW: [Option<DevSW>; NDEV] = {
@@ -35,8 +36,10 @@ pub struct Inode {
     r#ref: usize, // Reference count.
 }
 
+/// map major device number to device functions.
+/// Both return the number of bytes transferred, or -1.
 #[derive(Clone, Copy)]
 pub struct DevSW {
-    read: fn(i32, u64, i32) -> i32,
-    write: fn(i32, u64, i32) -> i32,
+    read: fn(UserSlice) -> i32,
+    write: fn(UserSlice) -> i32,
 }
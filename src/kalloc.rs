@@ -1,21 +1,53 @@
-use core::ptr;
+//! Physical memory allocator, for user processes,
+//! kernel stacks, page-table pages,
+//! and pipe buffers. Allocates blocks of 2^order
+//! physically contiguous 4096-byte pages.
 
-use crate::{memlayout::PHYSTOP, spinlock::SpinMutex};
-use alloc::alloc::{GlobalAlloc, Layout};
+use core::{
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicU16, AtomicU8, AtomicUsize, Ordering},
+};
 
-#[global_allocator]
-static ALLOCATOR: SpinMutex<BumpAllocator> = SpinMutex::new("kmem", BumpAllocator::new());
+use crate::{
+    memlayout::{KERNBASE, PHYSTOP},
+    param::NCPU,
+    println,
+    proc::cpuid,
+    riscv::{pg_round_up, PGSIZE},
+    spinlock::{pop_off, push_off, SpinMutex},
+    vm::PhysAddr,
+};
 
-#[alloc_error_handler]
-fn alloc_error_handler(layout: Layout) -> ! {
-    panic!("allocation error: {:?}", layout);
-}
+static KMEM: SpinMutex<BuddyAllocator> = SpinMutex::new("kmem", BuddyAllocator::new());
+
+/// Free pages cached by each CPU, indexed by hart.
+static PAGE_CACHES: [SpinMutex<PageCache>; NCPU] = {
+    const PCP: SpinMutex<PageCache> = SpinMutex::new("pcp", PageCache::new());
+    [PCP; NCPU]
+};
+
+/// Pages handed to the allocator at boot.
+static TOTAL_PAGES: AtomicUsize = AtomicUsize::new(0);
+/// Pages in use for each MemTag.
+static USED_PAGES: [AtomicUsize; NTAGS] = {
+    const ZERO: AtomicUsize = AtomicUsize::new(0);
+    [ZERO; NTAGS]
+};
+/// Tag of the allocated block starting at each page.
+static PAGE_TAGS: [AtomicU8; NPAGES] = {
+    const TAG: AtomicU8 = AtomicU8::new(MemTag::Other as u8);
+    [TAG; NPAGES]
+};
+/// Number of references to the allocated block starting at each page.
+/// Pages shared copy-on-write have more than one.
+static PAGE_REFS: [AtomicU16; NPAGES] = {
+    const ZERO: AtomicU16 = AtomicU16::new(0);
+    [ZERO; NPAGES]
+};
 
 pub fn kinit() {
     unsafe {
-        ALLOCATOR
-            .lock()
-            .init(end.as_ptr() as usize, PHYSTOP as usize);
+        free_range(end.as_ptr() as u64, PHYSTOP);
     }
 }
 
@@ -25,68 +57,464 @@ extern "C" {
     static end: [u8; 0];
 }
 
-pub struct BumpAllocator {
-    heap_start: usize,
-    heap_end: usize,
-    next: usize,
-    allocations: usize,
+/// Hand every whole page in [pa_start, pa_end) to the allocator.
+/// These pages were never allocated, so they bypass kfree()'s accounting.
+unsafe fn free_range(pa_start: u64, pa_end: u64) {
+    let mut kmem = KMEM.lock();
+    let mut pa = pg_round_up(pa_start);
+    while pa + PGSIZE <= pa_end {
+        kmem.free(PhysFrame::from_start_address(PhysAddr::new(pa)), 0);
+        TOTAL_PAGES.fetch_add(1, Ordering::Relaxed);
+        pa += PGSIZE;
+    }
+}
+
+/// Allocate one 4096-byte page of physical memory,
+/// accounted to tag. Returns None if the memory
+/// cannot be allocated.
+pub fn kalloc(tag: MemTag) -> Option<PhysFrame> {
+    alloc_pages(0, tag)
+}
+
+/// Free the page of physical memory pointed at by frame,
+/// which normally should have been returned by a
+/// call to kalloc(). (The exception is when
+/// initializing the allocator; see kinit above.)
+/// A shared page is only freed when its last reference goes.
+pub fn kfree(frame: PhysFrame) {
+    free_pages(frame, 0)
+}
+
+/// Add a reference to the allocated page at pa, which will take
+/// one more kfree() to free.
+pub fn page_get(pa: PhysAddr) {
+    let refs = PAGE_REFS[page_index(pa.as_u64())].fetch_add(1, Ordering::Relaxed);
+    assert!(refs > 0, "page_get: free page");
+}
+
+/// The number of references to the allocated page at pa.
+pub fn page_refs(pa: PhysAddr) -> usize {
+    PAGE_REFS[page_index(pa.as_u64())].load(Ordering::Acquire) as usize
+}
+
+/// Allocate 2^order physically contiguous pages, aligned to their size,
+/// and account them to tag. Returns the frame of the first page, or
+/// None if no block that large is free.
+pub fn alloc_pages(order: usize, tag: MemTag) -> Option<PhysFrame> {
+    assert!(order <= MAX_ORDER, "alloc_pages: order too large");
+
+    let frame = if order == 0 {
+        pcp_alloc()?
+    } else {
+        KMEM.lock().alloc(order)?
+    };
+
+    // fill with junk
+    unsafe {
+        ptr::write_bytes(frame.as_mut_ptr::<u8>(), 5, (PGSIZE as usize) << order);
+    }
+
+    let index = page_index(frame.start_address().as_u64());
+    PAGE_REFS[index].store(1, Ordering::Relaxed);
+    PAGE_TAGS[index].store(tag as u8, Ordering::Relaxed);
+    USED_PAGES[tag as usize].fetch_add(1 << order, Ordering::Relaxed);
+
+    Some(frame)
+}
+
+/// Drop a reference to a block of 2^order pages returned by
+/// alloc_pages(order). Once the last reference is gone, the block is
+/// freed, merging it with its buddy whenever the buddy is free too.
+pub fn free_pages(frame: PhysFrame, order: usize) {
+    assert!(order <= MAX_ORDER, "free_pages: order too large");
+
+    let index = page_index(frame.start_address().as_u64());
+    match PAGE_REFS[index].fetch_sub(1, Ordering::AcqRel) {
+        0 => panic!("free_pages: free block"),
+        1 => {}
+        _ => return,
+    }
+
+    let tag = PAGE_TAGS[index].load(Ordering::Relaxed);
+    USED_PAGES[tag as usize].fetch_sub(1 << order, Ordering::Relaxed);
+
+    // Fill with junk to catch dangling refs.
+    unsafe {
+        ptr::write_bytes(frame.as_mut_ptr::<u8>(), 1, (PGSIZE as usize) << order);
+    }
+
+    if order == 0 {
+        pcp_free(frame);
+    } else {
+        KMEM.lock().free(frame, order);
+    }
 }
 
-impl BumpAllocator {
-    /// Creates a new empty allocator.
-    pub const fn new() -> Self {
-        BumpAllocator {
-            heap_start: 0,
-            heap_end: 0,
-            next: 0,
-            allocations: 0,
+/// Take a page from this CPU's cache, refilling it from KMEM
+/// or, if KMEM is empty too, stealing from another CPU.
+fn pcp_alloc() -> Option<PhysFrame> {
+    push_off();
+    let id = cpuid();
+
+    let frame = {
+        let mut pcp = PAGE_CACHES[id].lock();
+        if pcp.count == 0 {
+            let mut kmem = KMEM.lock();
+            while pcp.count < PCP_BATCH {
+                match kmem.alloc(0) {
+                    Some(frame) => pcp.push(frame),
+                    None => break,
+                }
+            }
+        }
+        pcp.pop()
+    };
+    let frame = frame.or_else(|| pcp_steal(id));
+
+    pop_off();
+    frame
+}
+
+/// Give a page to this CPU's cache, draining a batch
+/// back to KMEM if the cache is full.
+fn pcp_free(frame: PhysFrame) {
+    push_off();
+
+    let mut pcp = PAGE_CACHES[cpuid()].lock();
+    if pcp.count == PCP_HIGH {
+        let mut kmem = KMEM.lock();
+        for _ in 0..PCP_BATCH {
+            kmem.free(pcp.pop().unwrap(), 0);
         }
     }
+    pcp.push(frame);
+    drop(pcp);
+
+    pop_off();
+}
+
+/// Move up to half of another CPU's cached pages to CPU id,
+/// returning one of them. Only one cache lock is held at a time.
+fn pcp_steal(id: usize) -> Option<PhysFrame> {
+    for victim in (0..NCPU).filter(|&i| i != id) {
+        let mut stolen = [PhysAddr::new(0); PCP_BATCH];
+        let n = {
+            let mut pcp = PAGE_CACHES[victim].lock();
+            let n = ((pcp.count + 1) / 2).min(PCP_BATCH);
+            for pa in stolen.iter_mut().take(n) {
+                *pa = pcp.pop().unwrap().into_start_address();
+            }
+            n
+        };
+        if n == 0 {
+            continue;
+        }
+
+        let mut pcp = PAGE_CACHES[id].lock();
+        for &pa in &stolen[1..n] {
+            pcp.push(PhysFrame(pa));
+        }
+        return Some(PhysFrame(stolen[0]));
+    }
+
+    None
+}
+
+/// The smallest order whose block holds `size` bytes.
+pub const fn order_for_size(size: usize) -> usize {
+    let mut order = 0;
+    while (PGSIZE as usize) << order < size {
+        order += 1;
+    }
+    order
+}
+
+/// What allocated pages are used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemTag {
+    PageTable,
+    KernelStack,
+    Heap,
+    User,
+    Other,
+}
+
+pub const NTAGS: usize = 5;
+
+impl MemTag {
+    const ALL: [MemTag; NTAGS] = [
+        MemTag::PageTable,
+        MemTag::KernelStack,
+        MemTag::Heap,
+        MemTag::User,
+        MemTag::Other,
+    ];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            MemTag::PageTable => "page tables",
+            MemTag::KernelStack => "kernel stacks",
+            MemTag::Heap => "kernel heap",
+            MemTag::User => "user pages",
+            MemTag::Other => "other",
+        }
+    }
+}
+
+/// A snapshot of physical memory usage, in pages.
+/// Laid out for copying to user space.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MemInfo {
+    pub total: u64,
+    pub free: u64,
+    pub used: [u64; NTAGS], // indexed by MemTag
+}
 
-    /// Initializes the allocator with the given heap bounds.
+/// Collect the current page counts.
+pub fn mem_info() -> MemInfo {
+    let mut free = {
+        let kmem = KMEM.lock();
+        let mut free = 0;
+        for (order, n) in kmem.nfree.iter().enumerate() {
+            free += (n << order) as u64;
+        }
+        free
+    };
+    for cache in &PAGE_CACHES {
+        free += cache.lock().count as u64;
+    }
+
+    let mut used = [0; NTAGS];
+    for (n, count) in used.iter_mut().zip(&USED_PAGES) {
+        *n = count.load(Ordering::Relaxed) as u64;
+    }
+
+    MemInfo {
+        total: TOTAL_PAGES.load(Ordering::Relaxed) as u64,
+        free,
+        used,
+    }
+}
+
+/// Print physical memory usage by purpose to console,
+/// then how the free memory is split up.
+/// Runs when user types ^T on console.
+pub fn mem_dump() {
+    let info = mem_info();
+    println!("{:14} {:6} pages", "total", info.total);
+    println!("{:14} {:6} pages", "free", info.free);
+    for tag in MemTag::ALL {
+        println!("{:14} {:6} pages", tag.name(), info.used[tag as usize]);
+    }
+    buddy_dump();
+}
+
+/// Print the number of free blocks of each order,
+/// and the pages cached by each CPU.
+fn buddy_dump() {
+    let nfree = KMEM.lock().nfree;
+    for (order, n) in nfree.iter().enumerate() {
+        println!(
+            "order {:2} ({:5} KiB): {} free",
+            order,
+            (PGSIZE >> 10) << order,
+            n
+        );
+    }
+    for (id, cache) in PAGE_CACHES.iter().enumerate() {
+        let count = cache.lock().count;
+        if count > 0 {
+            println!("cpu {}: {} pages cached", id, count);
+        }
+    }
+}
+
+/// A physical page frame owned by whoever holds this value.
+///
+/// Frames are not freed on drop: ownership usually moves into a page
+/// table mapping, and the frame must be handed back with [`kfree`] when
+/// that mapping goes away.
+pub struct PhysFrame(PhysAddr);
+
+impl PhysFrame {
+    /// Reclaims ownership of the frame starting at `pa`.
     ///
-    /// This function is unsafe because the caller must guarantee that the
-    /// given heap bounds are valid and that the heap is unused. This method
-    /// must be called only once.
-    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
-        self.heap_start = heap_start;
-        self.heap_end = heap_start + heap_size;
-        self.next = heap_start;
+    /// # Safety
+    ///
+    /// `pa` must name a page of allocatable RAM that nobody else owns,
+    /// typically one obtained earlier from [`PhysFrame::into_start_address`].
+    pub unsafe fn from_start_address(pa: PhysAddr) -> PhysFrame {
+        if !pa.is_aligned() || pa.as_u64() < end.as_ptr() as u64 || pa.as_u64() >= PHYSTOP {
+            panic!("kfree");
+        }
+        PhysFrame(pa)
+    }
+
+    /// Gives up ownership of the frame, returning its physical address.
+    #[inline]
+    pub fn into_start_address(self) -> PhysAddr {
+        self.0
+    }
+
+    #[inline]
+    pub fn start_address(&self) -> PhysAddr {
+        self.0
+    }
+
+    #[inline]
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.0.as_u64() as *mut T
     }
 }
 
-unsafe impl GlobalAlloc for SpinMutex<BumpAllocator> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut allocator = self.lock();
+/// Most pages a CPU caches before draining a batch to KMEM.
+const PCP_HIGH: usize = 64;
+/// Pages moved between a CPU's cache and KMEM at a time.
+const PCP_BATCH: usize = 16;
 
-        let size = layout.size();
-        let align = layout.align();
+/// A small stack of free pages private to one CPU, so the common
+/// single-page kalloc()/kfree() can skip the global KMEM lock.
+/// Other CPUs only lock it to steal pages.
+///
+/// Cached pages are free but not in KMEM, so the buddy allocator
+/// can't merge them with their buddies: up to PCP_HIGH pages per
+/// CPU may keep larger blocks from forming.
+struct PageCache {
+    pages: [PhysAddr; PCP_HIGH],
+    count: usize,
+}
 
-        // `Layout` contract forbids making a `Layout` with align=0, or align not power of 2.
-        // So we can safely use a mask to ensure alignment without worrying about UB.
-        let align_mask_to_round_down = !(align - 1);
+impl PageCache {
+    const fn new() -> Self {
+        PageCache {
+            pages: [PhysAddr::new(0); PCP_HIGH],
+            count: 0,
+        }
+    }
 
-        let alloc_start = (allocator.next + align - 1) & align_mask_to_round_down;
-        let alloc_end = match alloc_start.checked_add(size) {
-            Some(end_addr) => end_addr,
-            None => return ptr::null_mut(),
-        };
+    fn push(&mut self, frame: PhysFrame) {
+        self.pages[self.count] = frame.into_start_address();
+        self.count += 1;
+    }
 
-        if alloc_end > allocator.heap_end {
-            ptr::null_mut()
-        } else {
-            allocator.next = alloc_end;
-            allocator.allocations += 1;
-            alloc_start as *mut u8
+    fn pop(&mut self) -> Option<PhysFrame> {
+        if self.count == 0 {
+            return None;
         }
+        self.count -= 1;
+        Some(PhysFrame(self.pages[self.count]))
     }
+}
+
+/// Largest block handed out by the buddy allocator is 2^MAX_ORDER pages.
+pub const MAX_ORDER: usize = 10;
+
+/// Number of page frames between KERNBASE and PHYSTOP.
+const NPAGES: usize = ((PHYSTOP - KERNBASE) / PGSIZE) as usize;
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
-        let mut allocator = self.lock();
+/// Marks a page that is not the head of a free block.
+const NOT_FREE: u8 = u8::MAX;
+
+/// A free block, threaded through its first page.
+struct Block {
+    next: Option<NonNull<Block>>,
+    prev: Option<NonNull<Block>>,
+}
+
+/// Binary buddy allocator over the pages between `end` and PHYSTOP.
+///
+/// A block of order k covers 2^k pages and starts at a page index
+/// (counted from KERNBASE) that is a multiple of 2^k, so the buddy of
+/// the block at index i is the block at index i ^ 2^k.
+struct BuddyAllocator {
+    free_lists: [Option<NonNull<Block>>; MAX_ORDER + 1],
+    nfree: [usize; MAX_ORDER + 1],
+    // order of the free block starting at each page, or NOT_FREE.
+    free_order: [u8; NPAGES],
+}
 
-        allocator.allocations -= 1;
-        if allocator.allocations == 0 {
-            allocator.next = allocator.heap_start;
+// The lists only point into physical pages the allocator owns.
+unsafe impl Send for BuddyAllocator {}
+
+impl BuddyAllocator {
+    const fn new() -> Self {
+        BuddyAllocator {
+            free_lists: [None; MAX_ORDER + 1],
+            nfree: [0; MAX_ORDER + 1],
+            free_order: [NOT_FREE; NPAGES],
         }
     }
+
+    fn alloc(&mut self, order: usize) -> Option<PhysFrame> {
+        // find the smallest free block that is large enough.
+        let mut k = (order..=MAX_ORDER).find(|&k| self.free_lists[k].is_some())?;
+        let block = self.free_lists[k].unwrap();
+        let index = page_index(block.as_ptr() as u64);
+        self.remove(index, k);
+
+        // split it, giving back the upper halves.
+        while k > order {
+            k -= 1;
+            self.insert(index + (1 << k), k);
+        }
+
+        Some(PhysFrame(PhysAddr::new(page_addr(index))))
+    }
+
+    fn free(&mut self, frame: PhysFrame, order: usize) {
+        let mut index = page_index(frame.into_start_address().as_u64());
+        let mut k = order;
+
+        while k < MAX_ORDER {
+            let buddy = index ^ (1 << k);
+            if buddy >= NPAGES || self.free_order[buddy] != k as u8 {
+                break;
+            }
+            self.remove(buddy, k);
+            index &= !(1 << k);
+            k += 1;
+        }
+
+        self.insert(index, k);
+    }
+
+    fn insert(&mut self, index: usize, order: usize) {
+        let block = page_addr(index) as *mut Block;
+        let next = self.free_lists[order];
+        unsafe {
+            block.write(Block { next, prev: None });
+            if let Some(mut next) = next {
+                next.as_mut().prev = NonNull::new(block);
+            }
+        }
+        self.free_lists[order] = NonNull::new(block);
+        self.free_order[index] = order as u8;
+        self.nfree[order] += 1;
+    }
+
+    fn remove(&mut self, index: usize, order: usize) {
+        let block = unsafe { &mut *(page_addr(index) as *mut Block) };
+        unsafe {
+            match block.prev {
+                Some(mut prev) => prev.as_mut().next = block.next,
+                None => self.free_lists[order] = block.next,
+            }
+            if let Some(mut next) = block.next {
+                next.as_mut().prev = block.prev;
+            }
+        }
+        self.free_order[index] = NOT_FREE;
+        self.nfree[order] -= 1;
+    }
+}
+
+#[inline]
+fn page_index(pa: u64) -> usize {
+    ((pa - KERNBASE) / PGSIZE) as usize
+}
+
+#[inline]
+fn page_addr(index: usize) -> u64 {
+    KERNBASE + index as u64 * PGSIZE
 }
@@ -1,10 +1,10 @@
 #![no_std]
 #![no_main]
 #![feature(alloc_error_handler)]
+#![feature(allocator_api)]
 #![feature(const_for)]
 #![feature(const_default_impls)]
 #![feature(const_trait_impl)]
-#![feature(const_weak_new)]
 #![feature(const_intoiterator_identity)]
 #![feature(const_mut_refs)]
 #![feature(sync_unsafe_cell)]
@@ -22,24 +22,45 @@ use proc::cpuid;
 extern crate alloc;
 
 mod console;
+mod error;
 mod file;
 mod kalloc;
 mod memlayout;
+mod mmap;
 mod param;
 mod plic;
 mod printf;
 mod proc;
 mod riscv;
+mod sched;
+#[cfg(feature = "selftest")]
+mod selftest;
+mod shm;
+mod slab;
 mod spinlock;
 mod start;
+mod swap;
+mod syscall;
+mod sysproc;
 mod trap;
+mod uaccess;
 mod uart;
+mod virtio_disk;
 mod vm;
 
-global_asm!(include_str!("asm/entry.S"));
-global_asm!(include_str!("asm/kernelvec.S"));
+global_asm!(include_str!("asm/entry.S"), stack0_slot = const start::STACK0_SLOT);
+global_asm!(include_str!("asm/kernelvec.S"), kstacksize = const param::KSTACKSIZE);
 global_asm!(include_str!("asm/trampoline.S"));
 global_asm!(include_str!("asm/swtch.S"));
+global_asm!(
+    include_str!("asm/initcode.S"),
+    selftest = const cfg!(feature = "selftest") as u8,
+    sys_fork = const syscall::SYS_FORK,
+    sys_exit = const syscall::SYS_EXIT,
+    sys_wait = const syscall::SYS_WAIT,
+    sys_kill = const syscall::SYS_KILL,
+    sys_sbrk = const syscall::SYS_SBRK,
+);
 
 static STARTED: AtomicBool = AtomicBool::new(false);
 
@@ -56,17 +77,20 @@ pub extern "C" fn main() -> ! {
         trap::trap_init_hart(); // install kernel trap vector
         plic::plic_init(); // set up interrupt controller
         plic::plic_init_hart(); // ask PLIC for device interrupts
+        virtio_disk::virtio_disk_init(); // emulated hard disk, for swap
+        #[cfg(feature = "selftest")]
+        selftest::selftest(); // kernel self tests
+        proc::userinit(); // first user process
         STARTED.store(true, Ordering::Release);
     } else {
         while !STARTED.load(Ordering::Acquire) {
             core::hint::spin_loop();
         }
         println!("Hart {} starting!", cpuid());
+        vm::kvminithart(); // turn on paging
+        trap::trap_init_hart(); // install kernel trap vector
+        plic::plic_init_hart(); // ask PLIC for device interrupts
     }
 
-    riscv::intr_on();
-
-    loop {
-        core::hint::spin_loop();
-    }
+    proc::scheduler();
 }
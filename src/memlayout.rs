@@ -17,7 +17,10 @@
 // end -- start of kernel page allocation area
 // PHYSTOP -- end RAM used by the kernel
 
-use crate::riscv::{MAXVA, PGSIZE};
+use crate::{
+    param::{KSTACKSIZE, NPROC},
+    riscv::{MAXVA, PGSIZE},
+};
 
 // qemu puts UART registers here in physical memory.
 pub const UART0: u64 = 0x10000000;
@@ -65,11 +68,28 @@ pub(crate) const KERNBASE: u64 = 0x80000000;
 pub(crate) const PHYSTOP: u64 = KERNBASE + 128 * 1024 * 1024;
 
 // map the trampoline page to the highest address,
-// in both user and kernel space.
+// in both user and kernel space. MAXVA, and so everything
+// laid out below it, depends on the paging mode (sv39/sv48).
 pub(crate) const TRAMPOLINE: u64 = MAXVA - PGSIZE;
 
+// User memory layout.
+// Address zero first:
+//   text
+//   original data and bss
+//   fixed-size stack
+//   expandable heap
+//   ...
+//   TRAPFRAME (p->trapframe, used by the trampoline)
+//   TRAMPOLINE (the same page as in the kernel)
+pub(crate) const TRAPFRAME: u64 = TRAMPOLINE - PGSIZE;
+
 // map kernel stacks beneath the trampoline,
 // each surrounded by invalid guard pages.
-pub const fn kstack(hart: usize) -> u64 {
-    TRAMPOLINE - (hart + 1) as u64 * (2 * PGSIZE)
+pub const fn kstack(p: usize) -> u64 {
+    TRAMPOLINE - (p + 1) as u64 * (KSTACKSIZE as u64 + PGSIZE)
+}
+
+// which process's kernel stack has its guard page at va, if any.
+pub fn kstack_guard(va: u64) -> Option<usize> {
+    (0..NPROC).find(|&p| kstack(p) - PGSIZE <= va && va < kstack(p))
 }
@@ -0,0 +1,114 @@
+//! Memory-mapped regions of user address spaces, created by mmap().
+//!
+//! Pages of anonymous regions are allocated on first touch, and are
+//! demand-zero. Shared memory segment regions map the segment's own
+//! pages. There is no file system yet, so no file-backed regions.
+
+use alloc::sync::Arc;
+
+use crate::{
+    shm::Segment,
+    vm::{PageTableEntryFlags, PhysAddr},
+};
+
+pub const PROT_NONE: i32 = 0x0;
+pub const PROT_READ: i32 = 0x1;
+pub const PROT_WRITE: i32 = 0x2;
+pub const PROT_EXEC: i32 = 0x4;
+
+pub const MAP_SHARED: i32 = 0x01;
+pub const MAP_PRIVATE: i32 = 0x02;
+pub const MAP_ANONYMOUS: i32 = 0x20;
+
+/// What a region's pages are filled from.
+#[derive(Clone)]
+pub enum Backing {
+    Anonymous,
+    Shm { segment: Arc<Segment>, offset: u64 },
+}
+
+/// A virtual memory area: a page-aligned range of user addresses
+/// [start, end) with the same protection and backing.
+#[derive(Clone)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    pub prot: i32,
+    pub flags: i32,
+    pub backing: Backing,
+}
+
+impl Vma {
+    #[inline]
+    pub fn contains(&self, va: u64) -> bool {
+        self.start <= va && va < self.end
+    }
+
+    #[inline]
+    pub fn is_shared(&self) -> bool {
+        self.flags & MAP_SHARED != 0
+    }
+
+    /// Whether an access to the region is allowed.
+    pub fn allows(&self, write: bool) -> bool {
+        if write {
+            self.prot & PROT_WRITE != 0
+        } else {
+            self.prot & (PROT_READ | PROT_EXEC) != 0
+        }
+    }
+
+    /// PTE flags for the region's pages.
+    pub fn pte_flags(&self) -> PageTableEntryFlags {
+        let mut flags = PageTableEntryFlags::USER;
+        if self.prot & PROT_READ != 0 {
+            flags |= PageTableEntryFlags::READABLE;
+        }
+        if self.prot & PROT_WRITE != 0 {
+            // risc-v has no write-only pages.
+            flags |= PageTableEntryFlags::READABLE | PageTableEntryFlags::WRITABLE;
+        }
+        if self.prot & PROT_EXEC != 0 {
+            flags |= PageTableEntryFlags::EXECUTABLE;
+        }
+        flags
+    }
+
+    /// The segment page to map at va0, if this is a shared memory
+    /// segment region.
+    pub fn segment_page(&self, va0: u64) -> Option<PhysAddr> {
+        match &self.backing {
+            Backing::Shm { segment, offset } => segment.page(offset + (va0 - self.start)),
+            _ => None,
+        }
+    }
+
+    /// The part of the region within [start, end).
+    pub fn slice(&self, start: u64, end: u64) -> Vma {
+        let backing = match &self.backing {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::Shm { segment, offset } => Backing::Shm {
+                segment: segment.clone(),
+                offset: offset + (start - self.start),
+            },
+        };
+        Vma {
+            start,
+            end,
+            prot: self.prot,
+            flags: self.flags,
+            backing,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum MmapError {
+    InvalidArgument,
+    /// No room left in the address space.
+    NoSpace,
+    OutOfMemory,
+    /// A file mapping, without MAP_ANONYMOUS: there is no
+    /// file system to map files from yet.
+    Unsupported,
+}
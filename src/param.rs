@@ -4,3 +4,15 @@ pub const NOFILE: usize = 16; // open files per process
 pub const NFILE: usize = 100; // open files per system
 pub const NINODE: usize = 50; // maximum number of active i-nodes
 pub const NDEV: usize = 10; // maximum major device number
+pub const SWAPSTART: u64 = 4000; // first disk sector of the swap area, past an xv6 file system
+pub const NSWAP: usize = 16384; // swap slots of one page each, a multiple of 64
+pub const KSTACKSIZE: usize = 4 * 4096; // bytes per kernel stack, a multiple of the page size
+pub const MAXPGACCESS: u64 = 4096; // most pages one pgaccess() call may scan
+pub const NSHM: usize = 32; // shared memory segments per system
+pub const SHMMAX: u64 = 4 * 1024 * 1024; // bytes per shared memory segment
+pub const MLFQLEVELS: usize = 3; // priority levels of the mlfq scheduler
+pub const MLFQQUANTUM: [usize; MLFQLEVELS] = [1, 2, 4]; // ticks per time slice at each level
+pub const MLFQBOOST: usize = 100; // ticks of CPU time between mlfq priority boosts
+pub const NTICKETS: usize = 10; // lottery and stride tickets of a new process
+pub const MAXTICKETS: usize = 1 << 16; // most tickets one process may hold
+pub const BALANCEINTERVAL: usize = 10; // ticks between run queue load balancing
@@ -1,23 +1,27 @@
 use crate::{
+    error::KernelError,
     file::{File, Inode},
+    kalloc::{kalloc, kfree, MemTag, PhysFrame},
     memlayout::{kstack, TRAMPOLINE},
-    param::{NCPU, NOFILE, NPROC},
+    param::{KSTACKSIZE, NCPU, NOFILE, NPROC},
     println,
+    sched::{check_tickets, Policy, SchedError, Scheduler, ALL_CPUS},
     spinlock::{guard_lock, pop_off, push_off, SpinMutex, SpinMutexGuard},
-    vm::{kvmmap, PageTable, PageTableEntryFlags, PhysAddr, VirtAddr},
+    swap::{swap_enabled, swap_write},
+    trap::usertrapret,
+    uaccess::UserPtr,
+    vm::{protect, try_kvmmap, AddressSpace, PageTable, PageTableEntryFlags, PhysAddr, VirtAddr},
 };
 use core::{
-    cell::{Ref, RefCell, UnsafeCell},
-    ptr,
-    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    array,
+    cell::{Cell, RefCell, RefMut, UnsafeCell},
+    cmp::Reverse,
+    fmt, mem, ptr, str,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
 };
 
 use crate::riscv::*;
-use alloc::{
-    boxed::Box,
-    string::String,
-    sync::{Arc, Weak},
-};
+use alloc::{boxed::Box, sync::Arc};
 
 pub static CPUS: Cpus = {
     const CPU: UnsafeCell<Cpu> = UnsafeCell::new(Cpu::new());
@@ -32,15 +36,174 @@ pub static PROCS: ProcList = {
     }
 };
 
+/// The CPUs running scheduler(), one bit each.
+static ONLINE: AtomicU64 = AtomicU64::new(0);
+
+/// Runnable processes to run on each CPU, indexed by hart.
+/// Any CPU may use any of them, under the queue's lock.
+static RUN_QUEUES: [SpinMutex<Policy>; NCPU] = {
+    const QUEUE: SpinMutex<Policy> = SpinMutex::new("runq", Policy::new());
+    [QUEUE; NCPU]
+};
+
+/// Number of kernel stacks try_proc_mapstacks() managed to map.
+static NKSTACK: AtomicUsize = AtomicUsize::new(0);
+
 /// initialize the proc table at boot time.
+/// slots without a kernel stack keep kstack 0 and are never used.
 pub fn proc_init() {
-    for (i, proc) in PROCS.list.iter().enumerate() {
+    for (i, proc) in PROCS.list.iter().enumerate().take(nkstack()) {
         proc.inner.borrow_mut().kstack = kstack(i);
     }
 }
 
+/// How many process slots have a kernel stack.
+pub fn nkstack() -> usize {
+    NKSTACK.load(Ordering::Relaxed)
+}
+
 extern "C" {
-    fn swtch(old: *const Context, new: *mut Context);
+    fn swtch(old: *mut Context, new: *const Context);
+
+    // in initcode.S
+    static initcode: u8;
+    static initcode_end: u8;
+}
+
+/// Set up the first user process, init, which is given the
+/// children of every process that exits. There is no exec() to
+/// run /init with, so it runs initcode, which only reaps them.
+pub fn userinit() {
+    let p = PROCS.alloc_proc().expect("userinit: no process slot");
+    assert!(
+        ptr::eq(p, ProcList::init_proc()),
+        "userinit: not the first process"
+    );
+
+    let mut inner = p.inner();
+    let trapframe = inner.trapframe.as_deref().unwrap() as *const TrapFrame as u64;
+    let mut pagetable =
+        AddressSpace::new(PhysAddr::new(trapframe)).expect("userinit: out of memory");
+
+    // allocate one user page and copy initcode's
+    // instructions and data into it.
+    let code = unsafe {
+        let start = ptr::addr_of!(initcode);
+        let len = ptr::addr_of!(initcode_end) as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    };
+    assert!(code.len() as u64 <= PGSIZE, "userinit: more than a page");
+    let sz = pagetable
+        .alloc(
+            0,
+            PGSIZE,
+            PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTABLE,
+        )
+        .expect("userinit: out of memory");
+    pagetable
+        .copy_out(VirtAddr::new(0), code)
+        .expect("userinit: copy_out failed");
+    // initcode writes neither its code nor its stack,
+    // so the page can be read-only.
+    protect(
+        pagetable.page_table_mut(),
+        VirtAddr::new(0),
+        PGSIZE,
+        PageTableEntryFlags::READABLE | PageTableEntryFlags::EXECUTABLE | PageTableEntryFlags::USER,
+    )
+    .expect("userinit: protect failed");
+    inner.pagetable = Some(pagetable);
+
+    // prepare for the very first "return" from kernel to user.
+    let tf = inner.trapframe.as_mut().unwrap();
+    tf.epc = 0; // user program counter
+    tf.sp = PGSIZE; // user stack pointer
+
+    drop(inner);
+
+    let mut proc = p.control.lock();
+    proc.name = ProcName::new("initcode");
+    proc.sz = sz;
+    PROCS.make_runnable(p, &mut proc);
+}
+
+/// A fork child's very first scheduling by scheduler()
+/// will swtch to forkret.
+fn forkret() -> ! {
+    // Still holding p->lock from scheduler.
+    let p = CPUS.myproc().unwrap();
+    unsafe {
+        p.control.force_unlock();
+    }
+    pop_off();
+
+    usertrapret();
+}
+
+/// Per-CPU process scheduler.
+/// Each CPU calls scheduler() after setting itself up.
+/// Scheduler never returns.  It loops, doing:
+///  - choose a process to run.
+///  - swtch to start running that process.
+///  - eventually that process transfers control
+///    via swtch back to the scheduler.
+///
+/// Each CPU runs the processes on its own run queue, and when
+/// that is empty steals one from another CPU's.
+pub fn scheduler() -> ! {
+    let cpu = CPUS.mycpu();
+    let id = cpuid();
+    cpu.proc = None;
+    ONLINE.fetch_or(1 << id, Ordering::Relaxed);
+
+    loop {
+        // The most recent process to run may have had interrupts
+        // turned off; enable them to avoid a deadlock if all
+        // processes are waiting. Then turn them back off
+        // to avoid a possible race between an interrupt
+        // and wfi.
+        intr_on();
+        intr_off();
+
+        let next = RUN_QUEUES[id].lock().pick_next();
+        let p = match next.or_else(|| PROCS.steal(id)) {
+            Some(i) => &PROCS.list[i],
+            None => {
+                // nothing to run; stop running on this core until an
+                // interrupt. wfi wakes for a pending one even with
+                // interrupts off, and intr_on() above then takes it.
+                wfi();
+                continue;
+            }
+        };
+
+        let mut proc = p.control.lock();
+        assert!(
+            proc.state == ProcState::Runnable,
+            "scheduler: picked a process that is not runnable"
+        );
+        if !p.may_run_on(id) {
+            // its affinity changed while it was queued here.
+            PROCS.make_runnable(p, &mut proc);
+            continue;
+        }
+
+        // Switch to chosen process.  It is the process's job
+        // to release its lock and then reacquire it
+        // before jumping back to us.
+        proc.state = ProcState::Running;
+        cpu.proc = Some(p);
+        unsafe {
+            swtch(
+                ptr::addr_of_mut!(cpu.context),
+                ptr::addr_of!((*p.inner.as_ptr()).context),
+            );
+        }
+
+        // Process is done running for now.
+        // It should have changed its p->state before coming back.
+        cpu.proc = None;
+    }
 }
 
 pub struct Cpus([UnsafeCell<Cpu>; NCPU]);
@@ -63,26 +226,271 @@ impl ProcList {
         NEXT_PID.fetch_add(1, Ordering::Relaxed)
     }
 
+    /// Look in the process table for an UNUSED proc that has a
+    /// kernel stack. If found, initialize state required to run
+    /// in the kernel, and return it in the USED state, which keeps
+    /// others away without p->lock. If there are no free procs, or
+    /// a memory allocation fails, return an error.
+    fn alloc_proc(&self) -> Result<&Proc, KernelError> {
+        let p = self
+            .list
+            .iter()
+            .take(nkstack())
+            .find(|p| {
+                let mut proc = p.control.lock();
+                if proc.state != ProcState::Unused {
+                    return false;
+                }
+                proc.pid = ProcList::alloc_pid();
+                proc.state = ProcState::Used;
+                true
+            })
+            .ok_or(KernelError::InvalidArgument)?;
+
+        // Allocate a trapframe page.
+        let trapframe = match Box::try_new(unsafe { mem::zeroed::<TrapFrame>() }) {
+            Ok(trapframe) => trapframe,
+            Err(_) => {
+                self.free_proc(p, &mut p.control.lock());
+                return Err(KernelError::OutOfMemory);
+            }
+        };
+
+        // Set up new context to start executing at forkret,
+        // which returns to user space.
+        let mut inner = p.inner();
+        inner.trapframe = Some(trapframe);
+        inner.context = Context::default();
+        inner.context.ra = forkret as usize;
+        inner.context.sp = (inner.kstack + KSTACKSIZE as u64) as usize;
+
+        Ok(p)
+    }
+
+    /// free a proc structure and the data hanging from it,
+    /// including user pages, once it has stopped running.
+    /// p->lock must be held.
+    fn free_proc(&self, p: &Proc, proc: &mut ProcControl) {
+        let mut inner = p.inner();
+        inner.trapframe = None;
+        inner.pagetable = None;
+
+        proc.pid = 0;
+        proc.name = ProcName::new("");
+        proc.sz = 0;
+        proc.chan = None;
+        proc.killed = false;
+        proc.xstate = 0;
+        proc.ticks = 0;
+        proc.state = ProcState::Unused;
+    }
+
+    /// init, the process orphans are given to. userinit() is
+    /// the first to allocate a slot, so it has the first.
+    fn init_proc() -> &'static Proc {
+        &PROCS.list[0]
+    }
+
+    /// Create a new process, copying the parent.
+    /// Sets up child kernel stack to return as if from fork() system call.
+    /// Returns the child's pid.
+    pub fn fork(&self) -> Result<usize, KernelError> {
+        let p = CPUS.myproc().unwrap();
+
+        // Allocate process.
+        let np = self.alloc_proc()?;
+
+        // Copy user memory, registers, open files and name from
+        // parent to child. Copying may sleep to swap pages in, so
+        // np->lock is not held; nobody touches a USED proc.
+        let copied = (|| {
+            let mut inner = p.inner();
+            let mut child = np.inner();
+
+            let trapframe = child.trapframe.as_deref().unwrap() as *const TrapFrame as u64;
+            let sz = p.sz();
+            let pagetable = inner
+                .pagetable
+                .as_mut()
+                .unwrap()
+                .copy(PhysAddr::new(trapframe), sz)?;
+
+            child.pagetable = Some(pagetable);
+            **child.trapframe.as_mut().unwrap() = **inner.trapframe.as_ref().unwrap();
+            // Cause fork to return 0 in the child.
+            child.trapframe.as_mut().unwrap().a0 = 0;
+            // increment reference counts on open file descriptors.
+            child.file = inner.file.clone();
+            child.cwd = inner.cwd.clone();
+            Ok(sz)
+        })();
+        let sz = match copied {
+            Ok(sz) => sz,
+            Err(err) => {
+                self.free_proc(np, &mut np.control.lock());
+                return Err(err);
+            }
+        };
+
+        {
+            let _wait = self.wait_lock.lock();
+            np.parent.set(Some(p));
+        }
+
+        // the child starts out on the parent's CPU, with its
+        // affinity and what the policy knows of it.
+        let cpu = p.cpu.load(Ordering::Relaxed);
+        np.cpu.store(cpu, Ordering::Relaxed);
+        np.affinity
+            .store(p.affinity.load(Ordering::Relaxed), Ordering::Relaxed);
+
+        let name = p.name();
+        let mut proc = np.control.lock();
+        proc.name = name;
+        proc.sz = sz;
+        RUN_QUEUES[cpu].lock().inherit(self.slot(p), self.slot(np));
+        self.make_runnable(np, &mut proc);
+
+        Ok(proc.pid)
+    }
+
+    /// Pass p's abandoned children to init.
+    /// Caller must hold wait_lock.
+    fn reparent(&self, p: &Proc) {
+        let init = ProcList::init_proc();
+        for pp in &self.list {
+            if pp.parent.get().map_or(false, |parent| ptr::eq(parent, p)) {
+                pp.parent.set(Some(init));
+                self.wakeup(init.chan());
+            }
+        }
+    }
+
+    /// Exit the current process.  Does not return.
+    /// An exited process remains in the zombie state
+    /// until its parent calls wait().
+    ///
+    /// Its memory is given back here rather than in wait(), so
+    /// that a process killed for memory frees it even if its
+    /// parent never waits; see oom_kill().
+    pub fn exit(&self, status: i32) -> ! {
+        let p = CPUS.myproc().unwrap();
+        if ptr::eq(p, ProcList::init_proc()) {
+            panic!("init exiting: status {}", status);
+        }
+
+        // Close all open files, and free user memory. We run on
+        // the kernel page table, so the user one can go.
+        {
+            let mut inner = p.inner();
+            for file in inner.file.iter_mut() {
+                *file = None;
+            }
+            inner.cwd = None;
+            inner.pagetable = None;
+        }
+        p.set_sz(0);
+
+        let wait = self.wait_lock.lock();
+
+        // Give any children to init.
+        self.reparent(p);
+
+        // Parent might be sleeping in wait().
+        self.wakeup(p.parent.get().unwrap().chan());
+
+        let mut proc = p.control.lock();
+        proc.xstate = status;
+        proc.state = ProcState::Zombie;
+
+        drop(wait);
+
+        // Jump into the scheduler, never to return.
+        ProcList::sched(&proc);
+        panic!("zombie exit");
+    }
+
+    /// Wait for a child process to exit and return its pid,
+    /// copying its exit status to addr unless that is null.
+    /// Fails if this process has no children, or is killed.
+    /// init waits even without children, for orphans to reap.
+    ///
+    /// copy_out() may sleep, so the status is copied after the
+    /// child is freed and the locks are dropped; if addr is bad,
+    /// the child is gone all the same.
+    pub fn wait(&self, addr: UserPtr<i32>) -> Result<usize, KernelError> {
+        let p = CPUS.myproc().unwrap();
+
+        let wait = self.wait_lock.lock();
+        let (pid, xstate) = 'found: loop {
+            // Scan through table looking for exited children.
+            let mut havekids = false;
+            for pp in &self.list {
+                if !pp.parent.get().map_or(false, |parent| ptr::eq(parent, p)) {
+                    continue;
+                }
+
+                // make sure the child isn't still in exit() or swtch().
+                let mut child = pp.control.lock();
+                havekids = true;
+                if child.state == ProcState::Zombie {
+                    // Found one.
+                    let found = (child.pid, child.xstate);
+                    pp.parent.set(None);
+                    self.free_proc(pp, &mut child);
+                    break 'found found;
+                }
+            }
+
+            // No point waiting if we don't have any children,
+            // unless reparent() may yet give us some.
+            let init = ptr::eq(p, ProcList::init_proc());
+            if (!havekids && !init) || p.killed() {
+                return Err(KernelError::InvalidArgument);
+            }
+
+            // Wait for a child to exit.
+            self.sleep(p.chan(), &wait);
+        };
+        drop(wait);
+
+        if !addr.is_null() {
+            addr.write(&xstate)?;
+        }
+        Ok(pid)
+    }
+
+    /// Kill the process with the given pid.
+    /// The victim won't exit until it tries to return
+    /// to user space (see usertrap() in trap.rs).
+    pub fn kill(&self, pid: usize) -> Result<(), KernelError> {
+        for p in &self.list {
+            let mut proc = p.control.lock();
+            if proc.pid == pid && proc.state != ProcState::Unused {
+                proc.killed = true;
+                if proc.state == ProcState::Sleeping {
+                    // Wake process from sleep().
+                    self.make_runnable(p, &mut proc);
+                }
+                return Ok(());
+            }
+        }
+        Err(KernelError::InvalidArgument)
+    }
+
     /// Wake up all processes sleeping on chan.
     /// Must be called without any p->lock.
     pub fn wakeup(&self, chan: usize) {
         for proc in &self.list {
-            let myproc = CPUS.myproc();
-
-            match myproc {
-                Some(myproc) => {
-                    if !ptr::eq(Arc::as_ptr(myproc), proc) {
-                        let mut p = proc.control.lock();
-                        if p.state == ProcState::Sleeping {
-                            if let Some(proc_chan) = p.chan {
-                                if proc_chan == chan {
-                                    p.state = ProcState::Runnable;
-                                }
-                            }
-                        }
-                    }
-                }
-                None => {}
+            // the timer interrupt wakes sleepers from the scheduler,
+            // with no process of its own.
+            if CPUS.myproc().map_or(false, |myproc| ptr::eq(myproc, proc)) {
+                continue;
+            }
+
+            let mut p = proc.control.lock();
+            if p.state == ProcState::Sleeping && p.chan == Some(chan) {
+                self.make_runnable(proc, &mut p);
             }
         }
     }
@@ -103,6 +511,7 @@ impl ProcList {
                 unsafe {
                     mutex.force_unlock();
                 }
+                pop_off();
 
                 // Go to sleep.
                 proc_ctrl.chan = Some(chan);
@@ -145,30 +554,311 @@ impl ProcList {
                 panic!("sched: interrupts are enabled");
             }
 
+            // the process may be in the middle of using its inner
+            // state, so don't borrow it.
             let intena = CPUS.mycpu().intena;
             unsafe {
                 swtch(
-                    ptr::addr_of!(myproc.inner.borrow().context),
-                    ptr::addr_of_mut!(CPUS.mycpu().context),
+                    ptr::addr_of_mut!((*myproc.inner.as_ptr()).context),
+                    ptr::addr_of!(CPUS.mycpu().context),
                 );
             }
             CPUS.mycpu().intena = intena;
         }
     }
 
+    /// Charge a timer tick to the process running on this CPU.
+    /// Returns whether it has used up its time slice, and should
+    /// give up the CPU.
+    pub fn tick(&self) -> bool {
+        let p = match CPUS.myproc() {
+            Some(p) => p,
+            None => return false,
+        };
+        let mut proc = p.control.lock();
+        proc.ticks += 1;
+        let id = cpuid();
+        // a process whose affinity no longer includes this
+        // CPU moves at the end of its tick.
+        RUN_QUEUES[id].lock().tick(self.slot(p)) || !p.may_run_on(id)
+    }
+
+    /// Give up the CPU for one scheduling round.
+    pub fn yield_cpu(&self) {
+        if let Some(p) = CPUS.myproc() {
+            let mut proc = p.control.lock();
+            self.make_runnable(p, &mut proc);
+            ProcList::sched(&proc);
+        }
+    }
+
+    /// Mark p runnable and put it on a run queue: that of the
+    /// CPU it last ran on, unless its affinity rules that out.
+    /// Must hold p->lock.
+    fn make_runnable(&self, p: &Proc, proc: &mut ProcControl) {
+        proc.state = ProcState::Runnable;
+
+        let i = self.slot(p);
+        let home = p.cpu.load(Ordering::Relaxed);
+        let allowed = p.affinity.load(Ordering::Relaxed) & ONLINE.load(Ordering::Relaxed);
+        let to = if allowed & (1 << home) != 0 || allowed == 0 {
+            home
+        } else {
+            allowed.trailing_zeros() as usize
+        };
+        if to != home {
+            let state = RUN_QUEUES[home].lock().export(i);
+            RUN_QUEUES[to].lock().import(i, state);
+            p.cpu.store(to, Ordering::Relaxed);
+        }
+        RUN_QUEUES[to].lock().enqueue(i);
+    }
+
+    /// Take a runnable process off CPU from's run queue that may
+    /// run on CPU to, and bring along what the policy knows of it.
+    /// Returns its slot, for the caller to run or enqueue on to.
+    fn migrate(&self, from: usize, to: usize) -> Option<usize> {
+        let (i, state) = {
+            let mut queue = RUN_QUEUES[from].lock();
+            let i = queue.steal(&|i| self.list[i].may_run_on(to))?;
+            (i, queue.export(i))
+        };
+        RUN_QUEUES[to].lock().import(i, state);
+        self.list[i].cpu.store(to, Ordering::Relaxed);
+        Some(i)
+    }
+
+    /// Find a process for idle CPU to to run, from the run queue
+    /// of the busiest CPU that has one it may take.
+    fn steal(&self, to: usize) -> Option<usize> {
+        let lens: [usize; NCPU] = array::from_fn(|c| RUN_QUEUES[c].lock().len());
+        let mut cpus: [usize; NCPU] = array::from_fn(|c| c);
+        cpus.sort_unstable_by_key(|&c| Reverse(lens[c]));
+
+        cpus.into_iter()
+            .filter(|&from| from != to && lens[from] > 0)
+            .find_map(|from| self.migrate(from, to))
+    }
+
+    /// Even out the run queues of the running CPUs, moving processes
+    /// from the busiest to the idlest while they differ by more than
+    /// one. Called every BALANCEINTERVAL ticks.
+    pub fn balance(&self) {
+        let online = ONLINE.load(Ordering::Relaxed);
+        let len = |c: usize| RUN_QUEUES[c].lock().len();
+
+        // every move narrows the gap, so this ends.
+        for _ in 0..NPROC {
+            let cpus = (0..NCPU).filter(|&c| online & (1 << c) != 0);
+            let (busiest, idlest) = match (
+                cpus.clone().max_by_key(|&c| len(c)),
+                cpus.min_by_key(|&c| len(c)),
+            ) {
+                (Some(busiest), Some(idlest)) => (busiest, idlest),
+                _ => return,
+            };
+            if len(busiest) <= len(idlest) + 1 {
+                return;
+            }
+            match self.migrate(busiest, idlest) {
+                Some(i) => RUN_QUEUES[idlest].lock().enqueue(i),
+                None => return,
+            }
+        }
+    }
+
+    /// Restrict the process with pid, or the calling process if
+    /// pid is 0, to the CPUs in mask, one bit per hart.
+    pub fn set_affinity(&self, pid: usize, mask: u64) -> Result<(), SchedError> {
+        let mask = mask & ALL_CPUS;
+        if mask & ONLINE.load(Ordering::Relaxed) == 0 {
+            return Err(SchedError::InvalidAffinity);
+        }
+
+        let me = CPUS.myproc().unwrap();
+        let p = if pid == 0 {
+            me
+        } else {
+            self.list
+                .iter()
+                .find(|p| {
+                    let proc = p.control.lock();
+                    proc.pid == pid && proc.state != ProcState::Unused
+                })
+                .ok_or(SchedError::NoProcess)?
+        };
+        // one queued or running elsewhere moves when it is next
+        // picked or ticks; see scheduler() and tick().
+        p.affinity.store(mask, Ordering::Relaxed);
+
+        push_off();
+        let moved = !p.may_run_on(cpuid());
+        pop_off();
+        if ptr::eq(p, me) && moved {
+            self.yield_cpu();
+        }
+        Ok(())
+    }
+
+    /// The index of p in the process table, which is how the
+    /// scheduling policy knows it.
+    fn slot(&self, p: &Proc) -> usize {
+        (p as *const Proc as usize - self.list.as_ptr() as usize) / mem::size_of::<Proc>()
+    }
+
+    /// Set the current process's share of the CPU, for policies
+    /// with tickets.
+    pub fn set_tickets(&self, tickets: usize) -> Result<(), SchedError> {
+        check_tickets(tickets)?;
+        let p = CPUS.myproc().unwrap();
+        let _proc = p.control.lock();
+        RUN_QUEUES[p.cpu.load(Ordering::Relaxed)]
+            .lock()
+            .set_tickets(self.slot(p), tickets);
+        Ok(())
+    }
+
+    /// Swap a user page out to disk to free its memory. Returns false
+    /// if there is nothing to swap, or nowhere to.
+    ///
+    /// The address spaces take turns at giving up a page: me, the
+    /// caller's own, in its process's place (or last, if it has none),
+    /// and the other processes' in theirs.
+    pub fn swap_out(&self, me: &mut AddressSpace) -> bool {
+        static HAND: AtomicUsize = AtomicUsize::new(0);
+
+        if !swap_enabled() {
+            return false;
+        }
+
+        let mine = CPUS.myproc().map_or(NPROC, |p| self.slot(p));
+        for _ in 0..=NPROC {
+            let i = HAND.fetch_add(1, Ordering::Relaxed) % (NPROC + 1);
+            let victim = if i == mine {
+                me.swap_victim()
+            } else if i < NPROC {
+                self.swap_victim_of(&self.list[i])
+            } else {
+                continue;
+            };
+            if let Some((slot, frame)) = victim {
+                // the PTE already points at the slot, so no locks
+                // are needed to wait for the disk.
+                swap_write(slot, frame.start_address());
+                kfree(frame);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    // another process's page table may only change under its p->lock,
+    // while it neither runs nor has inner borrowed, as it would
+    // asleep in the middle of a page fault.
+    fn swap_victim_of(&self, p: &Proc) -> Option<(usize, PhysFrame)> {
+        let proc = p.control.lock();
+        if !matches!(proc.state, ProcState::Runnable | ProcState::Sleeping) {
+            return None;
+        }
+        let mut inner = p.inner.try_borrow_mut().ok()?;
+        inner.pagetable.as_mut()?.swap_victim()
+    }
+
+    /// Kill the user process with the largest memory size, so the
+    /// rest can have its memory. Called when user memory can't be
+    /// allocated even by swapping. Returns the victim's pid, or
+    /// None if there is nothing to kill.
+    ///
+    /// A victim only frees its memory once it has exited, so while
+    /// a killed process is still on its way out, no new victim is
+    /// chosen and that one is returned instead.
+    pub fn oom_kill(&self) -> Option<usize> {
+        let mut victim: Option<(&Proc, u64)> = None;
+        for p in &self.list {
+            let proc = p.control.lock();
+            if matches!(proc.state, ProcState::Unused | ProcState::Zombie)
+                || ptr::eq(p, ProcList::init_proc())
+            {
+                // init must not die.
+                continue;
+            }
+            if proc.killed {
+                return Some(proc.pid);
+            }
+            let sz = proc.sz;
+            if victim.map_or(true, |(_, max)| sz > max) {
+                victim = Some((p, sz));
+            }
+        }
+
+        let (p, sz) = match victim {
+            Some(victim) => victim,
+            None => {
+                println!("oom: out of memory, no process to kill");
+                return None;
+            }
+        };
+        let mut proc = p.control.lock();
+        if matches!(proc.state, ProcState::Unused | ProcState::Zombie) {
+            // it exited meanwhile, freeing its memory.
+            return Some(proc.pid);
+        }
+        proc.killed = true;
+        if proc.state == ProcState::Sleeping {
+            // Wake process from sleep().
+            self.make_runnable(p, &mut proc);
+        }
+        println!(
+            "oom: killed pid {} ({}), the largest process at {} KiB",
+            proc.pid,
+            proc.name,
+            sz / 1024
+        );
+        Some(proc.pid)
+    }
+
+    /// Run f, which allocates memory for the current process, again
+    /// for as long as it runs out and oom_kill() finds a victim other
+    /// than the caller, yielding meanwhile so the victim can exit.
+    /// Returns f's error once there is no one else to kill.
+    pub fn retry_oom<T, E>(&self, mut f: impl FnMut() -> Result<T, E>) -> Result<T, E>
+    where
+        E: Copy + Into<KernelError>,
+    {
+        let p = CPUS.myproc().unwrap();
+        loop {
+            match f() {
+                Err(err) if err.into() == KernelError::OutOfMemory && !p.killed() => {
+                    match self.oom_kill() {
+                        Some(pid) if pid != p.pid() => self.yield_cpu(),
+                        _ => return Err(err),
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
     /// Print a process listing to console.  For debugging.
     /// Runs when user types ^P on console.
     /// No lock to avoid wedging a stuck machine further.
     pub fn proc_dump(&self) {
+        println!("pid state    cpu level ticks name");
         for p in &self.list {
-            let (pid, state) = {
+            let (pid, state, ticks, name) = {
                 let proc = p.control.lock();
-                (proc.pid, proc.state)
+                (proc.pid, proc.state, proc.ticks, proc.name)
             };
             if matches!(state, ProcState::Unused) {
                 continue;
             }
-            println!("{} {:8?} {}", pid, state, p.inner.borrow().name);
+            let cpu = p.cpu.load(Ordering::Relaxed);
+            let level = RUN_QUEUES[cpu].lock().level(self.slot(p));
+            println!(
+                "{:3} {:8?} {:3} {:5} {:5} {}",
+                pid, state, cpu, level, ticks, name
+            );
         }
     }
 }
@@ -187,9 +877,9 @@ impl Cpus {
         unsafe { &mut *self.0[id].get() }
     }
 
-    pub fn myproc(&self) -> Option<&Arc<Proc>> {
+    pub fn myproc(&self) -> Option<&'static Proc> {
         push_off();
-        let p = self.mycpu().proc.as_ref();
+        let p = self.mycpu().proc;
         pop_off();
         p
     }
@@ -240,10 +930,11 @@ impl Context {
 
 // Per-CPU state.
 pub struct Cpu {
-    pub proc: Option<Arc<Proc>>, // The process running on this cpu, or null.
-    pub context: Context,        // swtch() here to enter scheduler().
-    pub noff: usize,             // Depth of push_off() nesting.
-    pub intena: bool,            // Were interrupts enabled before push_off()?
+    pub proc: Option<&'static Proc>, // The process running on this cpu, or null.
+    pub context: Context,            // swtch() here to enter scheduler().
+    pub noff: usize,                 // Depth of push_off() nesting.
+    pub intena: bool,                // Were interrupts enabled before push_off()?
+    pub asid_generation: usize,      // ASID generation this cpu's TLB was last flushed for.
 }
 
 impl Cpu {
@@ -253,6 +944,7 @@ impl Cpu {
             context: Context::default(),
             noff: 0,
             intena: false,
+            asid_generation: 0,
         }
     }
 }
@@ -269,21 +961,69 @@ pub struct Proc {
     control: SpinMutex<ProcControl>,
 
     // wait_lock must be held when using this:
-    parent: Weak<Proc>, // The parent process
+    parent: Cell<Option<&'static Proc>>, // The parent process
+
+    // used by the schedulers of all CPUs, without locks:
+    cpu: AtomicUsize,    // CPU whose run queue has this process
+    affinity: AtomicU64, // CPUs it may run on, one bit each
 
     inner: RefCell<ProcInner>,
 }
 
 /// these are private to the process, so lock need not be held.
-struct ProcInner {
-    kstack: u64,                       // Virtual address of kernel stack
-    sz: u64,                           // Size of process memory (bytes)
-    pagetable: usize,                  // User-level page table
-    trapframe: Option<Box<TrapFrame>>, // data page for trampoline.S
-    context: Context,                  // swtch() here to run process.
-    file: [Option<Arc<File>>; NOFILE], // open files
-    cwd: Option<Arc<Inode>>,           // current working directory
-    name: String,                      // Process name.
+pub struct ProcInner {
+    pub kstack: u64,                       // Virtual address of kernel stack
+    pub pagetable: Option<AddressSpace>,   // User-level page table
+    pub trapframe: Option<Box<TrapFrame>>, // data page for trampoline.S
+    pub context: Context,                  // swtch() here to run process.
+    pub file: [Option<Arc<File>>; NOFILE], // open files
+    pub cwd: Option<Arc<Inode>>,           // current working directory
+}
+
+impl Proc {
+    /// The process's private state. Only the process itself
+    /// (or its creator, before it first runs) may use this.
+    pub fn inner(&self) -> RefMut<'_, ProcInner> {
+        self.inner.borrow_mut()
+    }
+
+    pub fn pid(&self) -> usize {
+        self.control.lock().pid
+    }
+
+    pub fn killed(&self) -> bool {
+        self.control.lock().killed
+    }
+
+    pub fn set_killed(&self) {
+        self.control.lock().killed = true;
+    }
+
+    /// Process name (debugging).
+    pub fn name(&self) -> ProcName {
+        self.control.lock().name
+    }
+
+    /// Size of process memory (bytes).
+    pub fn sz(&self) -> u64 {
+        self.control.lock().sz
+    }
+
+    /// Only the process itself changes its size, once it runs.
+    pub fn set_sz(&self, sz: u64) {
+        self.control.lock().sz = sz;
+    }
+
+    /// The channel a process sleeps on in wait(), for its
+    /// children to wake it by.
+    fn chan(&self) -> usize {
+        self as *const Proc as usize
+    }
+
+    /// Whether the process's affinity allows it to run on CPU id.
+    pub fn may_run_on(&self, id: usize) -> bool {
+        self.affinity.load(Ordering::Relaxed) & (1 << id) != 0
+    }
 }
 
 impl const Default for Proc {
@@ -291,16 +1031,16 @@ impl const Default for Proc {
         const FILE: Option<Arc<File>> = None;
         Proc {
             control: SpinMutex::new("proc", ProcControl::default()),
-            parent: Weak::new(),
+            parent: Cell::new(None),
+            cpu: AtomicUsize::new(0),
+            affinity: AtomicU64::new(ALL_CPUS),
             inner: RefCell::new(ProcInner {
                 kstack: 0,
-                sz: 0,
-                pagetable: 0,
+                pagetable: None,
                 trapframe: None,
                 context: Context::default(),
                 file: [FILE; NOFILE],
                 cwd: None,
-                name: String::new(),
             }),
         }
     }
@@ -312,6 +1052,9 @@ pub struct ProcControl {
     killed: bool,        // Has the process been killed?
     xstate: i32,         // Process exit status to be returned to parent's wait.
     pid: usize,          // Process ID.
+    ticks: usize,        // CPU ticks used in all.
+    sz: u64,             // Size of process memory (bytes), for oom_kill().
+    name: ProcName,      // Process name (debugging).
 }
 
 impl const Default for ProcControl {
@@ -322,7 +1065,41 @@ impl const Default for ProcControl {
             killed: false,
             xstate: 0,
             pid: 0,
+            ticks: 0,
+            sz: 0,
+            name: ProcName::new(""),
+        }
+    }
+}
+
+/// A process name, kept in a fixed array as in xv6, so that it is
+/// copied out under p->lock rather than borrowed.
+#[derive(Clone, Copy)]
+pub struct ProcName([u8; 16]);
+
+impl ProcName {
+    /// name, cut short to fit.
+    const fn new(name: &str) -> Self {
+        let bytes = name.as_bytes();
+        let mut buf = [0; 16];
+        let mut i = 0;
+        while i < bytes.len() && i < buf.len() {
+            buf[i] = bytes[i];
+            i += 1;
         }
+        ProcName(buf)
+    }
+}
+
+impl fmt::Display for ProcName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let len = self.0.iter().position(|&c| c == 0).unwrap_or(self.0.len());
+        // cutting it short may have split a character.
+        let name = match str::from_utf8(&self.0[..len]) {
+            Ok(name) => name,
+            Err(err) => str::from_utf8(&self.0[..err.valid_up_to()]).unwrap(),
+        };
+        f.write_str(name)
     }
 }
 
@@ -349,66 +1126,70 @@ enum ProcState {
 // the trapframe includes callee-saved user registers like s0-s11 because the
 // return-to-user path via usertrapret() doesn't return through
 // the entire kernel call stack.
-#[repr(C)]
-struct TrapFrame {
-    kernel_satp: u64,   // kernel page table
-    kernel_sp: u64,     // top of process's kernel stack
-    kernel_trap: u64,   // usertrap()
-    epc: u64,           // saved user program counter
-    kernel_hartid: u64, // saved kernel tp
-    ra: u64,            // saved user return address
-    sp: u64,            // saved user stack pointer
-    gp: u64,            // saved user global pointer
-    tp: u64,            // saved user trap pointer
-    t0: u64,
-    t1: u64,
-    t2: u64,
-    s0: u64,
-    s1: u64,
-    a0: u64,
-    a1: u64,
-    a2: u64,
-    a3: u64,
-    a4: u64,
-    a5: u64,
-    a6: u64,
-    a7: u64,
-    s2: u64,
-    s3: u64,
-    s4: u64,
-    s5: u64,
-    s6: u64,
-    s7: u64,
-    s8: u64,
-    s9: u64,
-    s10: u64,
-    s11: u64,
-    t3: u64,
-    t4: u64,
-    t5: u64,
-    t6: u64,
+#[derive(Clone, Copy)]
+#[repr(C, align(4096))]
+pub struct TrapFrame {
+    pub kernel_satp: u64,   // kernel page table
+    pub kernel_sp: u64,     // top of process's kernel stack
+    pub kernel_trap: u64,   // usertrap()
+    pub epc: u64,           // saved user program counter
+    pub kernel_hartid: u64, // saved kernel tp
+    pub ra: u64,            // saved user return address
+    pub sp: u64,            // saved user stack pointer
+    pub gp: u64,            // saved user global pointer
+    pub tp: u64,            // saved user trap pointer
+    pub t0: u64,
+    pub t1: u64,
+    pub t2: u64,
+    pub s0: u64,
+    pub s1: u64,
+    pub a0: u64,
+    pub a1: u64,
+    pub a2: u64,
+    pub a3: u64,
+    pub a4: u64,
+    pub a5: u64,
+    pub a6: u64,
+    pub a7: u64,
+    pub s2: u64,
+    pub s3: u64,
+    pub s4: u64,
+    pub s5: u64,
+    pub s6: u64,
+    pub s7: u64,
+    pub s8: u64,
+    pub s9: u64,
+    pub s10: u64,
+    pub s11: u64,
+    pub t3: u64,
+    pub t4: u64,
+    pub t5: u64,
+    pub t6: u64,
 }
 
-/// Allocate a page for each process's kernel stack.
-/// Map it high in memory, followed by an invalid
-/// guard page.
-pub fn proc_mapstacks(kpgtbl: &mut PageTable) {
+/// Allocate KSTACKSIZE bytes for each process's kernel stack.
+/// Map them high in memory, above an invalid guard page.
+/// If memory runs out, the stacks mapped so far are kept, and
+/// nkstack() says how many there are.
+pub fn try_proc_mapstacks(kpgtbl: &mut PageTable) -> Result<(), KernelError> {
     for i in 0..NPROC {
-        let ptr = unsafe {
-            alloc::alloc::alloc_zeroed(alloc::alloc::Layout::array::<u8>(PGSIZE as usize).unwrap())
-        };
+        for off in (0..KSTACKSIZE as u64).step_by(PGSIZE as usize) {
+            let frame = kalloc(MemTag::KernelStack).ok_or(KernelError::OutOfMemory)?;
+            let pa = frame.into_start_address();
 
-        if ptr.is_null() {
-            panic!("proc_mapstacks: out of memory");
+            let va = kstack(i) + off;
+            if let Err(err) = try_kvmmap(
+                kpgtbl,
+                VirtAddr::new(va),
+                pa,
+                PGSIZE,
+                PageTableEntryFlags::READABLE | PageTableEntryFlags::WRITABLE,
+            ) {
+                kfree(unsafe { PhysFrame::from_start_address(pa) });
+                return Err(err);
+            }
         }
-
-        let va = kstack(i);
-        kvmmap(
-            kpgtbl,
-            VirtAddr::new(va),
-            PhysAddr::new(ptr as u64),
-            PGSIZE,
-            PageTableEntryFlags::READABLE | PageTableEntryFlags::WRITABLE,
-        );
+        NKSTACK.store(i + 1, Ordering::Relaxed);
     }
+    Ok(())
 }
@@ -44,6 +44,7 @@ pub(crate) fn w_mepc(mepc: usize) {
 }
 
 // Supervisor Status Register, sstatus
+pub(crate) const SSTATUS_SUM: usize = 1 << 18; // Supervisor may access User memory.
 pub(crate) const SSTATUS_SPP: usize = 1 << 8; // Previous mode, 1 = Supervisor, 0 = User.
 pub(crate) const SSTATUS_SPIE: usize = 1 << 5; // Supervisor Previous Interrupt Enable
 pub(crate) const SSTATUS_UPIE: usize = 1 << 4; // User Previous Interrupt Enable
@@ -218,11 +219,22 @@ pub(crate) fn w_pmpaddr0(pmpaddr0: usize) {
     }
 }
 
-// use riscv's sv39 page table scheme.
+// use riscv's sv39 page table scheme, or sv48
+// when built with the "sv48" feature.
 pub(crate) const SATP_SV39: u64 = 8 << 60;
+pub(crate) const SATP_SV48: u64 = 9 << 60;
 
-pub(crate) const fn make_satp(pagetable: u64) -> u64 {
-    SATP_SV39 | pagetable >> 12
+#[cfg(not(feature = "sv48"))]
+pub(crate) const SATP_MODE: u64 = SATP_SV39;
+#[cfg(feature = "sv48")]
+pub(crate) const SATP_MODE: u64 = SATP_SV48;
+
+// the address-space identifier field of satp.
+pub(crate) const SATP_ASID_SHIFT: u64 = 44;
+pub(crate) const SATP_ASID_MASK: u64 = 0xffff;
+
+pub(crate) const fn make_satp(pagetable: u64, asid: u16) -> u64 {
+    SATP_MODE | (asid as u64) << SATP_ASID_SHIFT | pagetable >> 12
 }
 
 // supervisor address translation and protection;
@@ -323,6 +335,15 @@ pub(crate) fn intr_get() -> bool {
     r_sstatus() & SSTATUS_SIE != 0
 }
 
+// Stall the hart until an interrupt is pending,
+// even if interrupts are disabled.
+#[inline(always)]
+pub(crate) fn wfi() {
+    unsafe {
+        asm!("wfi");
+    }
+}
+
 #[inline(always)]
 pub(crate) fn r_sp() -> usize {
     let mut sp: usize;
@@ -368,6 +389,22 @@ pub(crate) fn sfence_vma() {
     }
 }
 
+// Flush the TLB entries of one address space.
+#[inline(always)]
+pub(crate) fn sfence_vma_asid(asid: u16) {
+    unsafe {
+        asm!("sfence.vma zero, {}", in(reg) asid as usize);
+    }
+}
+
+// Flush the TLB entries for one page of one address space.
+#[inline(always)]
+pub(crate) fn sfence_vma_page(va: u64, asid: u16) {
+    unsafe {
+        asm!("sfence.vma {}, {}", in(reg) va, in(reg) asid as usize);
+    }
+}
+
 pub const PGSIZE: u64 = 4096; // bytes per page
 pub const PGSHIFT: u64 = 12; // bits of offset within a page
 
@@ -386,10 +423,20 @@ pub const fn pte2pa(pte: u64) -> u64 {
     (pte >> 10) << PGSHIFT
 }
 
-// extract the three 9-bit page table indices from a virtual address.
+// number of page-table levels: three for sv39, four for sv48.
+#[cfg(not(feature = "sv48"))]
+pub const PTLEVELS: u8 = 3;
+#[cfg(feature = "sv48")]
+pub const PTLEVELS: u8 = 4;
+
+// extract the 9-bit page table indices from a virtual address.
 pub const PXMASK: u64 = 0x1ff; // 9-bit mask for page table index.
 pub const fn pg_index(level: u8, addr: u64) -> u64 {
     (addr >> (PGSHIFT + (level * 9) as u64)) & PXMASK
 }
 
-pub const MAXVA: u64 = 1 << (9 + 9 + 9 + 12 - 1);
+// one beyond the highest possible virtual address.
+// MAXVA is actually one bit less than the max allowed by
+// sv39/sv48, to avoid having to sign-extend virtual addresses
+// that have the high bit set.
+pub const MAXVA: u64 = 1 << (9 * PTLEVELS as u64 + PGSHIFT - 1);
@@ -0,0 +1,476 @@
+//! Scheduling policies.
+//!
+//! Each CPU has a run queue: an instance of the policy holding the
+//! runnable processes that run there, which its scheduler loop in
+//! proc.rs asks which to run next. Processes are named by their slot
+//! in the process table. A process is enqueued whenever it becomes
+//! runnable, and pick_next() takes it off again to run it. That is
+//! the only way off a queue, besides steal(): a runnable process
+//! stays runnable until it runs, even once killed, since it must
+//! run to exit.
+//!
+//! What a policy knows about a process (its level, tickets, ...)
+//! lives in the run queue of the CPU the process last ran on, and
+//! goes along with it, by export() and import(), when the process
+//! moves to another CPU: when an idle CPU steals it, when the load
+//! balancer moves it, or when its affinity changes.
+//!
+//! The policy is chosen when building: round-robin by default,
+//! or one of the `mlfq`, `lottery` and `stride` features.
+
+use crate::param::{MAXTICKETS, MLFQBOOST, MLFQLEVELS, MLFQQUANTUM, NCPU, NPROC, NTICKETS};
+
+#[cfg(any(
+    all(feature = "mlfq", feature = "lottery"),
+    all(feature = "mlfq", feature = "stride"),
+    all(feature = "lottery", feature = "stride"),
+))]
+compile_error!("pick at most one of the mlfq, lottery and stride features");
+
+#[cfg(not(any(feature = "mlfq", feature = "lottery", feature = "stride")))]
+pub type Policy = RoundRobin;
+#[cfg(feature = "mlfq")]
+pub type Policy = Mlfq;
+#[cfg(feature = "lottery")]
+pub type Policy = Lottery;
+#[cfg(feature = "stride")]
+pub type Policy = Stride;
+
+/// A set of CPUs a process may run on, one bit per hart.
+pub const ALL_CPUS: u64 = (1 << NCPU) - 1;
+
+pub trait Scheduler {
+    /// What the policy keeps about each process.
+    type State: Copy;
+
+    /// Process p has become runnable.
+    fn enqueue(&mut self, p: usize);
+
+    /// Choose the runnable process to run next, and dequeue it.
+    fn pick_next(&mut self) -> Option<usize>;
+
+    /// Process p, running, got a timer tick. Returns whether it
+    /// should give up the CPU.
+    fn tick(&mut self, p: usize) -> bool;
+
+    /// Give process p a share of the CPU in proportion to
+    /// tickets. Only policies with tickets use it.
+    fn set_tickets(&mut self, _p: usize, _tickets: usize) {}
+
+    /// The priority level of process p, for proc_dump().
+    fn level(&self, _p: usize) -> usize {
+        0
+    }
+
+    /// Number of processes queued.
+    fn len(&self) -> usize;
+
+    /// Dequeue a process that may_move allows to leave for another
+    /// CPU, preferring one that would otherwise run last.
+    fn steal(&mut self, may_move: &dyn Fn(usize) -> bool) -> Option<usize>;
+
+    /// What this queue knows about process p, which is leaving.
+    fn export(&self, p: usize) -> Self::State;
+
+    /// Take over what another queue knew about process p,
+    /// which is arriving, before it is enqueued or run.
+    fn import(&mut self, p: usize, state: Self::State);
+
+    /// Start process child, newly forked, off with what this
+    /// queue knows about its parent.
+    fn inherit(&mut self, parent: usize, child: usize) {
+        let state = self.export(parent);
+        self.import(child, state);
+    }
+}
+
+#[derive(Debug)]
+pub enum SchedError {
+    /// A ticket count of 0, or more than MAXTICKETS.
+    InvalidTickets,
+    /// An affinity mask without any running CPU.
+    InvalidAffinity,
+    /// No process has the pid.
+    NoProcess,
+}
+
+/// Check a ticket count passed to set_tickets().
+pub fn check_tickets(tickets: usize) -> Result<(), SchedError> {
+    if tickets == 0 || tickets > MAXTICKETS {
+        return Err(SchedError::InvalidTickets);
+    }
+    Ok(())
+}
+
+/// A FIFO of process slots. Each process is in it at most once,
+/// so it never holds more than NPROC.
+pub struct RunQueue {
+    slots: [usize; NPROC],
+    head: usize,
+    len: usize,
+}
+
+impl RunQueue {
+    pub const fn new() -> Self {
+        RunQueue {
+            slots: [0; NPROC],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn push(&mut self, p: usize) {
+        assert!(self.len < NPROC, "runqueue: full");
+        self.slots[(self.head + self.len) % NPROC] = p;
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        let p = self.slots[self.head];
+        self.head = (self.head + 1) % NPROC;
+        self.len -= 1;
+        Some(p)
+    }
+
+    /// Take the process nearest the tail that f accepts
+    /// out of the queue.
+    pub fn remove_last(&mut self, f: impl Fn(usize) -> bool) -> Option<usize> {
+        let i = (0..self.len)
+            .rev()
+            .find(|&i| f(self.slots[(self.head + i) % NPROC]))?;
+        let p = self.slots[(self.head + i) % NPROC];
+        // close the gap.
+        for j in i..self.len - 1 {
+            self.slots[(self.head + j) % NPROC] = self.slots[(self.head + j + 1) % NPROC];
+        }
+        self.len -= 1;
+        Some(p)
+    }
+}
+
+/// Run each process for one tick in turn.
+pub struct RoundRobin {
+    queue: RunQueue,
+}
+
+impl RoundRobin {
+    pub const fn new() -> Self {
+        RoundRobin {
+            queue: RunQueue::new(),
+        }
+    }
+}
+
+/// What round-robin keeps about a process: nothing, since
+/// every process gets the same turn.
+#[derive(Clone, Copy)]
+pub struct NoState;
+
+impl Scheduler for RoundRobin {
+    type State = NoState;
+
+    fn enqueue(&mut self, p: usize) {
+        self.queue.push(p);
+    }
+
+    fn pick_next(&mut self) -> Option<usize> {
+        self.queue.pop()
+    }
+
+    fn tick(&mut self, _p: usize) -> bool {
+        true
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn steal(&mut self, may_move: &dyn Fn(usize) -> bool) -> Option<usize> {
+        self.queue.remove_last(may_move)
+    }
+
+    fn export(&self, _p: usize) -> NoState {
+        NoState
+    }
+
+    fn import(&mut self, _p: usize, _state: NoState) {}
+}
+
+/// Multi-level feedback queue: round-robin at each of MLFQLEVELS
+/// levels, always running the highest level (0) that has anything
+/// runnable. A process that uses up its time slice at a level is
+/// moved down one; every MLFQBOOST ticks of CPU time all are moved
+/// back to the top, so CPU-bound ones are not starved.
+pub struct Mlfq {
+    queues: [RunQueue; MLFQLEVELS],
+    level: [usize; NPROC],
+    slice: [usize; NPROC], // ticks used of the current time slice
+    ticks: usize,
+}
+
+impl Mlfq {
+    pub const fn new() -> Self {
+        const QUEUE: RunQueue = RunQueue::new();
+        Mlfq {
+            queues: [QUEUE; MLFQLEVELS],
+            level: [0; NPROC],
+            slice: [0; NPROC],
+            ticks: 0,
+        }
+    }
+
+    fn boost(&mut self) {
+        for level in 1..MLFQLEVELS {
+            while let Some(p) = self.queues[level].pop() {
+                self.queues[0].push(p);
+            }
+        }
+        self.level = [0; NPROC];
+        self.slice = [0; NPROC];
+    }
+}
+
+impl Scheduler for Mlfq {
+    /// Level, and ticks used of the time slice.
+    type State = (usize, usize);
+
+    fn enqueue(&mut self, p: usize) {
+        self.queues[self.level[p]].push(p);
+    }
+
+    fn pick_next(&mut self) -> Option<usize> {
+        self.queues.iter_mut().find_map(|queue| queue.pop())
+    }
+
+    fn tick(&mut self, p: usize) -> bool {
+        self.ticks += 1;
+        if self.ticks % MLFQBOOST == 0 {
+            self.boost();
+            return true;
+        }
+
+        self.slice[p] += 1;
+        if self.slice[p] < MLFQQUANTUM[self.level[p]] {
+            return false;
+        }
+
+        // a process that used up its slice is not interactive;
+        // move it down a level. one that sleeps first keeps
+        // the ticks it used, so it can't stay on top by
+        // sleeping just before the end of each slice.
+        self.slice[p] = 0;
+        if self.level[p] + 1 < MLFQLEVELS {
+            self.level[p] += 1;
+        }
+        true
+    }
+
+    fn level(&self, p: usize) -> usize {
+        self.level[p]
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(RunQueue::len).sum()
+    }
+
+    fn steal(&mut self, may_move: &dyn Fn(usize) -> bool) -> Option<usize> {
+        self.queues
+            .iter_mut()
+            .rev()
+            .find_map(|queue| queue.remove_last(may_move))
+    }
+
+    fn export(&self, p: usize) -> (usize, usize) {
+        (self.level[p], self.slice[p])
+    }
+
+    fn import(&mut self, p: usize, (level, slice): (usize, usize)) {
+        self.level[p] = level;
+        self.slice[p] = slice;
+    }
+}
+
+/// Lottery scheduling: each tick goes to a runnable process
+/// drawn at random, weighted by its tickets.
+pub struct Lottery {
+    runnable: [bool; NPROC],
+    tickets: [usize; NPROC],
+    rand: Rand,
+}
+
+impl Lottery {
+    pub const fn new() -> Self {
+        Lottery {
+            runnable: [false; NPROC],
+            tickets: [NTICKETS; NPROC],
+            rand: Rand::new(0x2545_f491_4f6c_dd1d),
+        }
+    }
+}
+
+impl Scheduler for Lottery {
+    /// Tickets.
+    type State = usize;
+
+    fn enqueue(&mut self, p: usize) {
+        self.runnable[p] = true;
+    }
+
+    fn pick_next(&mut self) -> Option<usize> {
+        let total: usize = (0..NPROC)
+            .filter(|&p| self.runnable[p])
+            .map(|p| self.tickets[p])
+            .sum();
+        if total == 0 {
+            return None;
+        }
+
+        let mut winner = self.rand.next() as usize % total;
+        for p in (0..NPROC).filter(|&p| self.runnable[p]) {
+            if winner < self.tickets[p] {
+                self.runnable[p] = false;
+                return Some(p);
+            }
+            winner -= self.tickets[p];
+        }
+        unreachable!("lottery: no winner");
+    }
+
+    fn tick(&mut self, _p: usize) -> bool {
+        true
+    }
+
+    fn set_tickets(&mut self, p: usize, tickets: usize) {
+        self.tickets[p] = tickets;
+    }
+
+    fn len(&self) -> usize {
+        self.runnable.iter().filter(|&&runnable| runnable).count()
+    }
+
+    fn steal(&mut self, may_move: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let p = (0..NPROC).find(|&p| self.runnable[p] && may_move(p))?;
+        self.runnable[p] = false;
+        Some(p)
+    }
+
+    fn export(&self, p: usize) -> usize {
+        self.tickets[p]
+    }
+
+    fn import(&mut self, p: usize, tickets: usize) {
+        self.tickets[p] = tickets;
+    }
+}
+
+/// Stride scheduling: the deterministic counterpart of lottery.
+/// Each process has a pass that advances by its stride, inversely
+/// proportional to its tickets, for every tick it runs; the one
+/// with the lowest pass runs next.
+pub struct Stride {
+    runnable: [bool; NPROC],
+    stride: [u64; NPROC],
+    pass: [u64; NPROC],
+}
+
+/// Stride of a process with one ticket.
+const STRIDE1: u64 = 1 << 20;
+
+impl Stride {
+    pub const fn new() -> Self {
+        Stride {
+            runnable: [false; NPROC],
+            stride: [STRIDE1 / NTICKETS as u64; NPROC],
+            pass: [0; NPROC],
+        }
+    }
+
+    /// The lowest pass of the runnable processes.
+    fn min_pass(&self) -> Option<u64> {
+        (0..NPROC)
+            .filter(|&q| self.runnable[q])
+            .map(|q| self.pass[q])
+            .min()
+    }
+}
+
+impl Scheduler for Stride {
+    /// Stride, and how far the pass is ahead of the queue's.
+    type State = (u64, u64);
+
+    fn enqueue(&mut self, p: usize) {
+        // a process back from sleeping must not be owed all the
+        // time it slept; start it level with the others.
+        if let Some(min) = self.min_pass() {
+            self.pass[p] = self.pass[p].max(min);
+        }
+        self.runnable[p] = true;
+    }
+
+    fn pick_next(&mut self) -> Option<usize> {
+        let p = (0..NPROC)
+            .filter(|&p| self.runnable[p])
+            .min_by_key(|&p| self.pass[p])?;
+        self.runnable[p] = false;
+        Some(p)
+    }
+
+    fn tick(&mut self, p: usize) -> bool {
+        self.pass[p] += self.stride[p];
+        true
+    }
+
+    fn set_tickets(&mut self, p: usize, tickets: usize) {
+        self.stride[p] = STRIDE1 / tickets as u64;
+    }
+
+    fn len(&self) -> usize {
+        self.runnable.iter().filter(|&&runnable| runnable).count()
+    }
+
+    fn steal(&mut self, may_move: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let p = (0..NPROC)
+            .filter(|&p| self.runnable[p] && may_move(p))
+            .max_by_key(|&p| self.pass[p])?;
+        self.runnable[p] = false;
+        Some(p)
+    }
+
+    // passes on different CPUs are not comparable, so a process
+    // takes along only how far ahead of the others it was.
+    fn export(&self, p: usize) -> (u64, u64) {
+        let base = self.min_pass().unwrap_or(self.pass[p]);
+        (self.stride[p], self.pass[p].saturating_sub(base))
+    }
+
+    fn import(&mut self, p: usize, (stride, ahead): (u64, u64)) {
+        self.stride[p] = stride;
+        self.pass[p] = self.min_pass().unwrap_or(0) + ahead;
+    }
+}
+
+/// xorshift64 pseudo-random numbers, good enough for lotteries.
+struct Rand(u64);
+
+impl Rand {
+    const fn new(seed: u64) -> Self {
+        Rand(seed)
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+}
@@ -0,0 +1,409 @@
+//! Kernel self tests, run at boot when built with
+//! `--features selftest`. Each test panics on failure.
+//!
+//! fork, exit, wait and kill need a user process to call them,
+//! so initcode checks those once it runs (see asm/initcode.S).
+
+use alloc::vec::Vec;
+use core::ptr;
+
+use crate::{
+    kalloc::{kalloc, kfree, mem_info, MemTag},
+    memlayout::{KERNBASE, PHYSTOP},
+    mmap::{Backing, MAP_ANONYMOUS, MAP_PRIVATE, MAP_SHARED, PROT_READ, PROT_WRITE},
+    println,
+    riscv::{
+        make_satp, pg_index, r_satp, r_sstatus, r_time, sfence_vma, w_satp, w_sstatus, PGSIZE,
+        PTLEVELS, SATP_ASID_MASK, SATP_ASID_SHIFT, SSTATUS_SUM,
+    },
+    sched::{Lottery, RoundRobin, Scheduler, Stride},
+    shm::{shm_get, shm_usage},
+    swap::{swap_enabled, swap_used},
+    vm::{AddressSpace, PageTable, PageTableEntryFlags, VirtAddr},
+};
+
+pub fn selftest() {
+    cow_stress();
+    mmap_fork();
+    shm_share();
+    sched_share();
+    asid_bench();
+    swap_stress();
+    println!("selftest: all passed");
+}
+
+/// Fill user memory [start, end) with a byte pattern.
+fn fill(space: &mut AddressSpace, start: u64, end: u64, pattern: u8) {
+    let buf = [pattern; 256];
+    for va in (start..end).step_by(buf.len()) {
+        space
+            .copy_out(VirtAddr::new(va), &buf)
+            .expect("fill: copy_out failed");
+    }
+}
+
+/// Check that user memory [start, end) holds a byte pattern.
+fn check(space: &mut AddressSpace, start: u64, end: u64, pattern: u8) {
+    let mut buf = [0; 256];
+    for va in (start..end).step_by(buf.len()) {
+        space
+            .copy_in(&mut buf, VirtAddr::new(va))
+            .expect("check: copy_in failed");
+        assert!(
+            buf.iter().all(|&b| b == pattern),
+            "check: bad data at {:#x}",
+            va
+        );
+    }
+}
+
+/// Fork many children off a parent with a large buffer, then have
+/// every child write its own pattern over the whole buffer.
+fn cow_stress() {
+    const NCHILD: usize = 32;
+    const NPAGES: u64 = 64;
+
+    let free_before = mem_info().free;
+
+    // the trapframe is only mapped, never used.
+    let trapframe = kalloc(MemTag::Other).unwrap();
+    let mut parent = AddressSpace::new(trapframe.start_address()).unwrap();
+    let sz = parent
+        .alloc(0, NPAGES * PGSIZE, PageTableEntryFlags::WRITABLE)
+        .unwrap();
+    fill(&mut parent, 0, sz, 0xaa);
+
+    let free_parent = mem_info().free;
+    let mut children = Vec::new();
+    for _ in 0..NCHILD {
+        children.push(parent.copy(trapframe.start_address(), sz).unwrap());
+    }
+
+    // forking only costs page-table pages, not the buffer: each
+    // child has a root table, one table per lower level for the
+    // buffer and one per lower level for the trampoline and
+    // trapframe. One more page each is slack for the heap, which
+    // holds children; copying the buffer would take NPAGES each.
+    let forked = free_parent - mem_info().free;
+    let tables = 2 * PTLEVELS as u64 - 1;
+    assert!(
+        forked <= NCHILD as u64 * (tables + 1),
+        "cow_stress: fork copied {} pages",
+        forked
+    );
+
+    for (i, child) in children.iter_mut().enumerate() {
+        check(child, 0, sz, 0xaa);
+        fill(child, 0, sz, i as u8);
+    }
+    check(&mut parent, 0, sz, 0xaa);
+    for (i, child) in children.iter_mut().enumerate() {
+        check(child, 0, sz, i as u8);
+    }
+
+    // the parent writes last, once it is the only one left.
+    drop(children);
+    fill(&mut parent, 0, sz, 0x55);
+    check(&mut parent, 0, sz, 0x55);
+
+    drop(parent);
+    kfree(trapframe);
+    assert_eq!(mem_info().free, free_before, "cow_stress: pages leaked");
+
+    println!("cow_stress: ok");
+}
+
+/// Map a private and a shared anonymous region, fork, and check that
+/// only writes to the shared one are seen by the other side.
+fn mmap_fork() {
+    const LEN: u64 = 8 * PGSIZE;
+    let prot = PROT_READ | PROT_WRITE;
+
+    let free_before = mem_info().free;
+
+    let trapframe = kalloc(MemTag::Other).unwrap();
+    let mut parent = AddressSpace::new(trapframe.start_address()).unwrap();
+    let private = parent
+        .mmap(LEN, prot, MAP_PRIVATE | MAP_ANONYMOUS, Backing::Anonymous)
+        .unwrap();
+    let shared = parent
+        .mmap(LEN, prot, MAP_SHARED | MAP_ANONYMOUS, Backing::Anonymous)
+        .unwrap();
+
+    // demand-zero, and nothing allocated until touched.
+    check(&mut parent, private, private + LEN, 0);
+    fill(&mut parent, private, private + LEN, 0xaa);
+    fill(&mut parent, shared, shared + LEN, 0xaa);
+
+    let mut child = parent.copy(trapframe.start_address(), 0).unwrap();
+    fill(&mut child, private, private + LEN, 0x11);
+    fill(&mut child, shared, shared + LEN, 0x22);
+    check(&mut parent, private, private + LEN, 0xaa);
+    check(&mut parent, shared, shared + LEN, 0x22);
+
+    // unmapping the middle of a region leaves both ends usable.
+    child.munmap(private + PGSIZE, PGSIZE).unwrap();
+    assert!(child
+        .copy_out(VirtAddr::new(private + PGSIZE), &[0])
+        .is_err());
+    check(&mut child, private, private + PGSIZE, 0x11);
+    check(&mut child, private + 2 * PGSIZE, private + LEN, 0x11);
+
+    drop(child);
+    check(&mut parent, shared, shared + LEN, 0x22);
+    parent.munmap(shared, LEN).unwrap();
+
+    drop(parent);
+    kfree(trapframe);
+    assert_eq!(mem_info().free, free_before, "mmap_fork: pages leaked");
+
+    println!("mmap_fork: ok");
+}
+
+/// Two address spaces attach the same segment, at different
+/// addresses, and see each other's writes. The segment outlives
+/// one detaching, and is freed when the last one goes.
+fn shm_share() {
+    const KEY: i32 = 0x5348;
+    const LEN: u64 = 4 * PGSIZE;
+
+    let free_before = mem_info().free;
+
+    let trapframe = kalloc(MemTag::Other).unwrap();
+    let mut a = AddressSpace::new(trapframe.start_address()).unwrap();
+    let mut b = AddressSpace::new(trapframe.start_address()).unwrap();
+    // shift b's regions down so the addresses differ.
+    b.mmap(
+        PGSIZE,
+        PROT_READ,
+        MAP_PRIVATE | MAP_ANONYMOUS,
+        Backing::Anonymous,
+    )
+    .unwrap();
+
+    let at_a = a.shmat(shm_get(KEY, LEN).unwrap()).unwrap();
+    // a size of 0 finds the existing segment.
+    let at_b = b.shmat(shm_get(KEY, 0).unwrap()).unwrap();
+    assert_ne!(at_a, at_b);
+    assert!(shm_get(KEY, 2 * LEN).is_err());
+    assert_eq!(shm_usage(), (1, (LEN / PGSIZE) as usize));
+
+    check(&mut b, at_b, at_b + LEN, 0);
+    fill(&mut a, at_a, at_a + LEN, 0x5a);
+    check(&mut b, at_b, at_b + LEN, 0x5a);
+
+    // a fork shares the segment too.
+    let mut child = b.copy(trapframe.start_address(), 0).unwrap();
+    fill(&mut child, at_b, at_b + LEN, 0x3c);
+    check(&mut a, at_a, at_a + LEN, 0x3c);
+    drop(child);
+
+    a.shmdt(at_a).unwrap();
+    assert!(a.shmdt(at_a).is_err());
+    check(&mut b, at_b, at_b + LEN, 0x3c);
+
+    // exit detaches.
+    drop(b);
+    assert_eq!(shm_usage(), (0, 0));
+
+    // the key now names a fresh segment.
+    let at_a = a.shmat(shm_get(KEY, LEN).unwrap()).unwrap();
+    check(&mut a, at_a, at_a + LEN, 0);
+    drop(a);
+
+    kfree(trapframe);
+    assert_eq!(mem_info().free, free_before, "shm_share: pages leaked");
+
+    println!("shm_share: ok");
+}
+
+/// Run three always-runnable processes holding 1, 2 and 3 parts
+/// of the tickets under each policy, and check the share of the
+/// ticks each got: in proportion for stride exactly and lottery
+/// roughly, and equal for round-robin, which ignores tickets.
+fn sched_share() {
+    const NTICKS: usize = 60000;
+    const TICKETS: [usize; 3] = [100, 200, 300];
+
+    // share of the ticks each process got, in tenths of a percent.
+    fn run(policy: &mut impl Scheduler) -> [usize; 3] {
+        for (p, &tickets) in TICKETS.iter().enumerate() {
+            policy.set_tickets(p, tickets);
+            policy.enqueue(p);
+        }
+
+        let mut ticks = [0; 3];
+        let mut running = None;
+        for _ in 0..NTICKS {
+            let p = match running {
+                Some(p) => p,
+                None => policy.pick_next().expect("sched_share: nothing to run"),
+            };
+            ticks[p] += 1;
+            running = if policy.tick(p) {
+                policy.enqueue(p);
+                None
+            } else {
+                Some(p)
+            };
+        }
+
+        ticks.map(|n| n * 1000 / NTICKS)
+    }
+
+    fn check(name: &str, got: [usize; 3], want: [usize; 3], slack: usize) {
+        println!(
+            "sched_share: {:8} {:3} {:3} {:3} (want {:3} {:3} {:3})",
+            name, got[0], got[1], got[2], want[0], want[1], want[2]
+        );
+        for (got, want) in got.iter().zip(want) {
+            assert!(
+                got.abs_diff(want) <= slack,
+                "sched_share: {} gave a share of {} instead of {}",
+                name,
+                got,
+                want
+            );
+        }
+    }
+
+    let total: usize = TICKETS.iter().sum();
+    let proportional = TICKETS.map(|tickets| tickets * 1000 / total);
+
+    check("rr", run(&mut RoundRobin::new()), [333; 3], 1);
+    check("stride", run(&mut Stride::new()), proportional, 1);
+    check("lottery", run(&mut Lottery::new()), proportional, 20);
+
+    println!("sched_share: ok");
+}
+
+/// Measure switching back and forth between two address spaces,
+/// touching a few pages of each after every switch, with a full
+/// TLB flush per switch (no ASIDs) and with ASIDs.
+fn asid_bench() {
+    const NSWITCH: usize = 10000;
+    const NPAGES: u64 = 16;
+
+    let kernel_satp = r_satp() as u64;
+    // the root page table is at satp's PPN field times PGSIZE.
+    let kernel = unsafe { &*((kernel_satp << 20 >> 8) as *const PageTable) };
+    // the kernel's part of the address space, so that this code
+    // keeps running after each switch. user memory here is only
+    // mmap regions at the top, clear of it.
+    let kernel_index = pg_index(PTLEVELS - 1, KERNBASE) as usize;
+
+    let trapframe = kalloc(MemTag::Other).unwrap();
+    let mut spaces = [(); 2].map(|_| AddressSpace::new(trapframe.start_address()).unwrap());
+    let mut regions = [0; 2];
+    for (space, region) in spaces.iter_mut().zip(regions.iter_mut()) {
+        *region = space
+            .mmap(
+                NPAGES * PGSIZE,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                Backing::Anonymous,
+            )
+            .unwrap();
+        fill(space, *region, *region + NPAGES * PGSIZE, 1);
+        space.page_table_mut()[kernel_index] = kernel[kernel_index];
+    }
+
+    let run = |satps: [u64; 2], flush: bool| {
+        w_sstatus(r_sstatus() | SSTATUS_SUM);
+        let start = r_time();
+        for i in 0..NSWITCH {
+            w_satp(satps[i % 2]);
+            if flush {
+                sfence_vma();
+            }
+            for page in 0..NPAGES {
+                let va = regions[i % 2] + page * PGSIZE;
+                unsafe { ptr::read_volatile(va as *const u8) };
+            }
+        }
+        let ticks = r_time() - start;
+        w_satp(kernel_satp);
+        sfence_vma();
+        w_sstatus(r_sstatus() & !SSTATUS_SUM);
+        ticks
+    };
+
+    let untagged = spaces
+        .each_ref()
+        .map(|space| make_satp(space.page_table() as *const PageTable as u64, 0));
+    let without = run(untagged, true);
+
+    let tagged = spaces.each_mut().map(|space| space.activate());
+    let has_asids = tagged[0] >> SATP_ASID_SHIFT & SATP_ASID_MASK != 0;
+    let with = run(tagged, !has_asids);
+
+    for space in spaces.iter_mut() {
+        space.page_table_mut()[kernel_index].clear();
+    }
+    drop(spaces);
+    kfree(trapframe);
+
+    if has_asids {
+        println!(
+            "asid_bench: {} switches: {} ticks without ASIDs, {} with",
+            NSWITCH, without, with
+        );
+    } else {
+        println!(
+            "asid_bench: {} switches: {} ticks; no ASIDs on this hart",
+            NSWITCH, without
+        );
+    }
+}
+
+/// Touch more user memory than there is physical memory, each page
+/// tagged with its own number, and check every page reads back right.
+fn swap_stress() {
+    if !swap_enabled() {
+        println!("swap_stress: skipped, no swap disk");
+        return;
+    }
+
+    // 16 MiB more than all of RAM.
+    let sz = PHYSTOP - KERNBASE + 16 * 1024 * 1024;
+    let tag = |va: u64| (va / PGSIZE) ^ 0x5a5a_5a5a_5a5a_5a5a;
+
+    let free_before = mem_info().free;
+
+    let trapframe = kalloc(MemTag::Other).unwrap();
+    let mut space = AddressSpace::new(trapframe.start_address()).unwrap();
+    space.set_heap_end(sz);
+
+    // the first and last words of each page.
+    for va in (0..sz).step_by(PGSIZE as usize) {
+        let bytes = tag(va).to_ne_bytes();
+        for off in [0, PGSIZE - 8] {
+            space
+                .copy_out(VirtAddr::new(va + off), &bytes)
+                .expect("swap_stress: copy_out failed");
+        }
+    }
+    assert!(swap_used() > 0, "swap_stress: nothing was swapped out");
+
+    for va in (0..sz).step_by(PGSIZE as usize) {
+        for off in [0, PGSIZE - 8] {
+            let mut bytes = [0; 8];
+            space
+                .copy_in(&mut bytes, VirtAddr::new(va + off))
+                .expect("swap_stress: copy_in failed");
+            assert_eq!(
+                u64::from_ne_bytes(bytes),
+                tag(va),
+                "swap_stress: bad data at {:#x}",
+                va + off
+            );
+        }
+    }
+
+    drop(space);
+    kfree(trapframe);
+    assert_eq!(swap_used(), 0, "swap_stress: swap slots leaked");
+    assert_eq!(mem_info().free, free_before, "swap_stress: pages leaked");
+
+    println!("swap_stress: ok");
+}
@@ -0,0 +1,145 @@
+//! Shared memory segments.
+//!
+//! A segment is a set of zeroed pages named by a key, which
+//! cooperating processes map into their address spaces with shmat()
+//! to share memory. It is like System V's shmget() and shmat() rolled
+//! into one: the first shmat() of a key creates the segment, and
+//! later ones map the same pages.
+//!
+//! Each mapping of a segment holds a reference to it (see
+//! mmap::Backing::Shm), so it lives until the last mapper unmaps it
+//! or exits. The table only remembers which key names which live
+//! segment; a slot whose segment is gone is free for reuse.
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::ptr;
+
+use crate::{
+    kalloc::{kalloc, kfree, MemTag, PhysFrame},
+    param::{NSHM, SHMMAX},
+    riscv::{pg_round_up, PGSIZE},
+    spinlock::SpinMutex,
+    vm::PhysAddr,
+};
+
+static SHM: SpinMutex<[Option<ShmSlot>; NSHM]> = {
+    const EMPTY: Option<ShmSlot> = None;
+    SpinMutex::new("shm", [EMPTY; NSHM])
+};
+
+struct ShmSlot {
+    key: i32,
+    segment: Weak<Segment>,
+}
+
+/// The pages of a shared memory segment.
+pub struct Segment {
+    pages: Vec<PhysAddr>,
+}
+
+impl Segment {
+    /// Size of the segment in bytes.
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.pages.len() as u64 * PGSIZE
+    }
+
+    /// The physical page at byte offset off.
+    pub fn page(&self, off: u64) -> Option<PhysAddr> {
+        self.pages.get((off / PGSIZE) as usize).copied()
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        for &pa in &self.pages {
+            kfree(unsafe { PhysFrame::from_start_address(pa) });
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ShmError {
+    /// Bad size, or a size larger than the existing segment.
+    InvalidArgument,
+    /// No free table slot.
+    NoSpace,
+    /// No memory for the pages.
+    OutOfMemory,
+}
+
+/// Look up the segment named key, creating it with size bytes
+/// if there is none. A size of 0 only looks up.
+pub fn shm_get(key: i32, size: u64) -> Result<Arc<Segment>, ShmError> {
+    if size > SHMMAX {
+        return Err(ShmError::InvalidArgument);
+    }
+
+    let mut table = SHM.lock();
+    let existing = table
+        .iter()
+        .flatten()
+        .filter(|slot| slot.key == key)
+        .find_map(|slot| slot.segment.upgrade());
+    if let Some(segment) = existing {
+        if size > segment.size() {
+            return Err(ShmError::InvalidArgument);
+        }
+        return Ok(segment);
+    }
+    if size == 0 {
+        return Err(ShmError::InvalidArgument);
+    }
+
+    let slot = table
+        .iter_mut()
+        .find(|slot| {
+            slot.as_ref()
+                .map_or(true, |slot| slot.segment.strong_count() == 0)
+        })
+        .ok_or(ShmError::NoSpace)?;
+
+    let npages = (pg_round_up(size) / PGSIZE) as usize;
+    let mut pages = Vec::new();
+    pages
+        .try_reserve_exact(npages)
+        .map_err(|_| ShmError::OutOfMemory)?;
+    for _ in 0..npages {
+        let frame = match kalloc(MemTag::User) {
+            Some(frame) => frame,
+            None => {
+                drop(Segment { pages });
+                return Err(ShmError::OutOfMemory);
+            }
+        };
+        unsafe {
+            ptr::write_bytes(frame.as_mut_ptr::<u8>(), 0, PGSIZE as usize);
+        }
+        pages.push(frame.into_start_address());
+    }
+
+    // on failure the segment is dropped, freeing its pages.
+    let segment = Arc::try_new(Segment { pages }).map_err(|_| ShmError::OutOfMemory)?;
+    *slot = Some(ShmSlot {
+        key,
+        segment: Arc::downgrade(&segment),
+    });
+
+    Ok(segment)
+}
+
+/// Number of live segments, and the pages they hold.
+#[cfg(feature = "selftest")]
+pub fn shm_usage() -> (usize, usize) {
+    let table = SHM.lock();
+    table
+        .iter()
+        .flatten()
+        .filter_map(|slot| slot.segment.upgrade())
+        .fold((0, 0), |(n, pages), segment| {
+            (n + 1, pages + segment.pages.len())
+        })
+}
@@ -0,0 +1,292 @@
+//! Slab allocator for small kernel heap objects.
+//!
+//! Objects of up to 2 KiB are served from per-size-class caches whose
+//! slabs are carved out of blocks from the buddy allocator; anything
+//! larger (or more strictly aligned) gets whole pages of its own.
+//! Each cache has its own lock, so `Box`, `Arc` and `String`
+//! allocations of different sizes don't contend with each other.
+
+use core::{
+    mem,
+    ptr::{self, NonNull},
+};
+
+use crate::{
+    kalloc::{alloc_pages, free_pages, mem_dump, order_for_size, MemTag, PhysFrame, MAX_ORDER},
+    println,
+    riscv::PGSIZE,
+    spinlock::SpinMutex,
+    vm::PhysAddr,
+};
+use alloc::alloc::{GlobalAlloc, Layout};
+
+#[global_allocator]
+static ALLOCATOR: SlabAllocator = SlabAllocator;
+
+/// Only infallible allocations (Box::new, Vec::push, ...) end up
+/// here. Anything whose size a user program controls goes through
+/// try_reserve() or try_new() instead and fails with an error, so
+/// this is the kernel itself running out at boot or a kernel bug.
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    mem_dump();
+    slab_dump();
+    panic!("allocation error: {:?}", layout);
+}
+
+/// Object sizes of the caches, smallest first.
+const SIZE_CLASSES: [usize; NCACHES] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+const NCACHES: usize = 8;
+
+/// Bytes reserved for the slab header at the start of every slab.
+/// Objects follow it, so no cached object is aligned to more than this.
+const SLAB_HEADER: usize = 64;
+
+static CACHES: [SpinMutex<SlabCache>; NCACHES] = [
+    SpinMutex::new("slab-16", SlabCache::new(SIZE_CLASSES[0])),
+    SpinMutex::new("slab-32", SlabCache::new(SIZE_CLASSES[1])),
+    SpinMutex::new("slab-64", SlabCache::new(SIZE_CLASSES[2])),
+    SpinMutex::new("slab-128", SlabCache::new(SIZE_CLASSES[3])),
+    SpinMutex::new("slab-256", SlabCache::new(SIZE_CLASSES[4])),
+    SpinMutex::new("slab-512", SlabCache::new(SIZE_CLASSES[5])),
+    SpinMutex::new("slab-1024", SlabCache::new(SIZE_CLASSES[6])),
+    SpinMutex::new("slab-2048", SlabCache::new(SIZE_CLASSES[7])),
+];
+
+/// Objects too large for any cache, and the pages backing them.
+static LARGE: SpinMutex<LargeStats> = SpinMutex::new("slab-large", LargeStats::new());
+
+/// Print per-cache usage. For debugging leaks.
+pub fn slab_dump() {
+    println!("cache      inuse  pages");
+    for cache in &CACHES {
+        let (size, inuse, pages) = {
+            let cache = cache.lock();
+            (cache.size, cache.inuse, cache.pages)
+        };
+        println!("{:6} {:10} {:6}", size, inuse, pages);
+    }
+    let large = LARGE.lock();
+    println!("large  {:10} {:6}", large.inuse, large.pages);
+}
+
+/// Which cache serves `layout`, if any.
+fn cache_index(layout: &Layout) -> Option<usize> {
+    if layout.align() > SLAB_HEADER {
+        return None;
+    }
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&class| size <= class)
+}
+
+/// An unallocated object, linked through its first word.
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// Header at the start of each slab.
+struct Slab {
+    next: Option<NonNull<Slab>>,
+    prev: Option<NonNull<Slab>>,
+    free: Option<NonNull<FreeObject>>,
+    inuse: usize,
+}
+
+/// A cache of equally-sized objects.
+///
+/// Slabs are buddy blocks of 2^order pages, so the slab owning an
+/// object is found by rounding the object's address down to the slab
+/// size. Only slabs with free objects are kept on the partial list;
+/// a slab whose last object is freed goes back to the buddy allocator.
+struct SlabCache {
+    size: usize,
+    order: usize,
+    partial: Option<NonNull<Slab>>,
+    inuse: usize, // objects handed out
+    pages: usize, // pages held by slabs
+}
+
+// The slab lists only point into pages the cache owns.
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    const fn new(size: usize) -> Self {
+        SlabCache {
+            size,
+            // larger objects get bigger slabs so the header wastes less.
+            order: if size >= 512 { 2 } else { 0 },
+            partial: None,
+            inuse: 0,
+            pages: 0,
+        }
+    }
+
+    #[inline]
+    const fn slab_bytes(&self) -> usize {
+        (PGSIZE as usize) << self.order
+    }
+
+    fn alloc(&mut self) -> *mut u8 {
+        let mut slab = match self.partial {
+            Some(slab) => slab,
+            None => match self.grow() {
+                Some(slab) => slab,
+                None => return ptr::null_mut(),
+            },
+        };
+
+        let slab = unsafe { slab.as_mut() };
+        let object = slab.free.expect("slab on partial list is full");
+        slab.free = unsafe { object.as_ref().next };
+        slab.inuse += 1;
+        self.inuse += 1;
+
+        if slab.free.is_none() {
+            self.unlink(slab);
+        }
+
+        object.as_ptr() as *mut u8
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        let slab = &mut *((ptr as usize & !(self.slab_bytes() - 1)) as *mut Slab);
+
+        if slab.free.is_none() {
+            // it was full; it has room again.
+            self.push(slab);
+        }
+
+        let object = ptr as *mut FreeObject;
+        object.write(FreeObject { next: slab.free });
+        slab.free = NonNull::new(object);
+        slab.inuse -= 1;
+        self.inuse -= 1;
+
+        if slab.inuse == 0 {
+            self.unlink(slab);
+            self.pages -= 1 << self.order;
+            free_pages(
+                PhysFrame::from_start_address(PhysAddr::new(slab as *mut Slab as u64)),
+                self.order,
+            );
+        }
+    }
+
+    /// Allocate a new slab and put it on the partial list.
+    fn grow(&mut self) -> Option<NonNull<Slab>> {
+        let frame = alloc_pages(self.order, MemTag::Heap)?;
+        self.pages += 1 << self.order;
+
+        let base = frame.into_start_address().as_u64() as usize;
+        let nobjects = (self.slab_bytes() - SLAB_HEADER) / self.size;
+
+        // thread the free list through the objects, lowest address first.
+        let mut free = None;
+        for i in (0..nobjects).rev() {
+            let object = (base + SLAB_HEADER + i * self.size) as *mut FreeObject;
+            unsafe {
+                object.write(FreeObject { next: free });
+            }
+            free = NonNull::new(object);
+        }
+
+        let slab = base as *mut Slab;
+        unsafe {
+            slab.write(Slab {
+                next: None,
+                prev: None,
+                free,
+                inuse: 0,
+            });
+            self.push(&mut *slab);
+        }
+
+        NonNull::new(slab)
+    }
+
+    fn push(&mut self, slab: &mut Slab) {
+        slab.prev = None;
+        slab.next = self.partial;
+        if let Some(mut next) = self.partial {
+            unsafe {
+                next.as_mut().prev = NonNull::new(slab);
+            }
+        }
+        self.partial = NonNull::new(slab);
+    }
+
+    fn unlink(&mut self, slab: &mut Slab) {
+        unsafe {
+            match slab.prev {
+                Some(mut prev) => prev.as_mut().next = slab.next,
+                None => self.partial = slab.next,
+            }
+            if let Some(mut next) = slab.next {
+                next.as_mut().prev = slab.prev;
+            }
+        }
+        slab.next = None;
+        slab.prev = None;
+    }
+}
+
+struct LargeStats {
+    inuse: usize,
+    pages: usize,
+}
+
+impl LargeStats {
+    const fn new() -> Self {
+        LargeStats { inuse: 0, pages: 0 }
+    }
+}
+
+/// The kernel heap: slab caches for small layouts, buddy blocks for
+/// the rest.
+pub struct SlabAllocator;
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(i) = cache_index(&layout) {
+            return CACHES[i].lock().alloc();
+        }
+
+        // blocks are aligned to their size, which covers any alignment
+        // up to the block size.
+        let order = order_for_size(layout.size().max(layout.align()));
+        if order > MAX_ORDER {
+            return ptr::null_mut();
+        }
+
+        match alloc_pages(order, MemTag::Heap) {
+            Some(frame) => {
+                let mut large = LARGE.lock();
+                large.inuse += 1;
+                large.pages += 1 << order;
+                frame.into_start_address().as_u64() as *mut u8
+            }
+            None => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(i) = cache_index(&layout) {
+            return CACHES[i].lock().dealloc(ptr);
+        }
+
+        let order = order_for_size(layout.size().max(layout.align()));
+        {
+            let mut large = LARGE.lock();
+            large.inuse -= 1;
+            large.pages -= 1 << order;
+        }
+        free_pages(
+            PhysFrame::from_start_address(PhysAddr::new(ptr as u64)),
+            order,
+        );
+    }
+}
+
+// a free object must fit in the smallest class.
+const _: () = assert!(mem::size_of::<FreeObject>() <= SIZE_CLASSES[0]);
+const _: () = assert!(mem::size_of::<Slab>() <= SLAB_HEADER);
@@ -1,13 +1,27 @@
-use core::arch::asm;
+use core::{arch::asm, ptr};
 
-use crate::{main, memlayout::*, param::NCPU, riscv::*};
+use crate::{
+    main,
+    memlayout::*,
+    param::{KSTACKSIZE, NCPU},
+    riscv::*,
+};
 
-#[repr(C, align(16))]
-struct Stack([u8; 4096 * NCPU]);
+/// Bytes of stack0 per CPU: a guard page, then the stack.
+pub const STACK0_SLOT: usize = PGSIZE as usize + KSTACKSIZE;
+
+#[repr(C, align(4096))]
+struct Stack([u8; STACK0_SLOT * NCPU]);
 
 // entry.S needs one stack per CPU.
 #[export_name = "stack0"]
-static mut STACK0: Stack = Stack([0; 4096 * NCPU]);
+static mut STACK0: Stack = Stack([0; STACK0_SLOT * NCPU]);
+
+/// The guard page below hart's boot stack, which
+/// kvmmake() leaves unmapped.
+pub fn stack0_guard(hart: usize) -> u64 {
+    unsafe { ptr::addr_of!(STACK0) as u64 + (hart * STACK0_SLOT) as u64 }
+}
 
 // a scratch area per CPU for machine-mode timer interrupts.
 static mut TIMER_SCRATCH: [[u64; 5]; NCPU] = [[0; 5]; NCPU];
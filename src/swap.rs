@@ -0,0 +1,120 @@
+//! Swap space: user pages paged out to a region of the disk.
+//!
+//! The swap area is NSWAP page-sized slots starting at disk sector
+//! SWAPSTART. A swapped-out page leaves a non-valid PTE holding its
+//! slot number behind (see vm.rs), and is read back in when touched.
+//!
+//! The PTE is switched before the page is written out, so that a
+//! process's page table can be changed under its lock and the disk
+//! waited for afterwards. Until swap_write() is done, the slot is
+//! marked as being written, and swap_read() of it waits.
+
+use core::ptr;
+
+use crate::{
+    param::{NSWAP, SWAPSTART},
+    proc::PROCS,
+    riscv::PGSIZE,
+    spinlock::SpinMutex,
+    virtio_disk::{virtio_disk_present, virtio_disk_rw, SECTOR_SIZE},
+    vm::PhysAddr,
+};
+
+static SWAP: SpinMutex<SwapMap> = SpinMutex::new("swap", SwapMap::new());
+
+/// Which swap slots are in use, and which are still being
+/// written out, one bit per slot.
+struct SwapMap {
+    used: [u64; NSWAP / 64],
+    writing: [u64; NSWAP / 64],
+}
+
+impl SwapMap {
+    const fn new() -> Self {
+        SwapMap {
+            used: [0; NSWAP / 64],
+            writing: [0; NSWAP / 64],
+        }
+    }
+}
+
+/// Whether there is a disk to swap to.
+pub fn swap_enabled() -> bool {
+    virtio_disk_present()
+}
+
+/// Allocate a swap slot, to be filled by swap_write(). Returns
+/// None if swap is full, or there is no swap disk.
+pub fn swap_alloc() -> Option<usize> {
+    if !swap_enabled() {
+        return None;
+    }
+
+    let mut swap = SWAP.lock();
+    // a freed slot may still have a write in flight.
+    let (i, word) = swap
+        .used
+        .iter()
+        .zip(swap.writing.iter())
+        .map(|(used, writing)| used | writing)
+        .enumerate()
+        .find(|(_, word)| *word != u64::MAX)?;
+    let bit = word.trailing_ones() as usize;
+    swap.used[i] |= 1 << bit;
+    swap.writing[i] |= 1 << bit;
+
+    Some(i * 64 + bit)
+}
+
+/// Free a swap slot.
+pub fn swap_free(slot: usize) {
+    let mut swap = SWAP.lock();
+    let (i, bit) = (slot / 64, slot % 64);
+    if swap.used[i] & (1 << bit) == 0 {
+        panic!("swap_free: slot {} not in use", slot);
+    }
+    swap.used[i] &= !(1 << bit);
+}
+
+/// Number of swap slots in use.
+#[cfg(feature = "selftest")]
+pub fn swap_used() -> usize {
+    let swap = SWAP.lock();
+    swap.used
+        .iter()
+        .map(|word| word.count_ones() as usize)
+        .sum()
+}
+
+/// Write the page at pa out to a newly allocated swap slot.
+pub fn swap_write(slot: usize, pa: PhysAddr) {
+    virtio_disk_rw(pa, PGSIZE as usize, slot_sector(slot), true);
+
+    let chan = {
+        let mut swap = SWAP.lock();
+        swap.writing[slot / 64] &= !(1 << (slot % 64));
+        ptr::addr_of!(swap.writing) as usize
+    };
+    // not under SWAP: the swapper takes SWAP holding a p->lock.
+    PROCS.wakeup(chan);
+}
+
+/// Read a swap slot into the page at pa.
+pub fn swap_read(slot: usize, pa: PhysAddr) {
+    // the page may still be on its way out.
+    let swap = SWAP.lock();
+    loop {
+        if swap.writing[slot / 64] & (1 << (slot % 64)) == 0 {
+            break;
+        }
+        PROCS.sleep(ptr::addr_of!(swap.writing) as usize, &swap);
+    }
+    drop(swap);
+
+    virtio_disk_rw(pa, PGSIZE as usize, slot_sector(slot), false);
+}
+
+fn slot_sector(slot: usize) -> u64 {
+    assert!(slot < NSWAP, "swap: bad slot");
+    SWAPSTART + (slot * (PGSIZE as usize / SECTOR_SIZE)) as u64
+}
@@ -0,0 +1,77 @@
+//! System call numbers and dispatch.
+
+use crate::{println, proc::CPUS, sysproc::*};
+
+// System call numbers, as in xv6. The ones this kernel
+// doesn't implement yet are left out until it does.
+pub const SYS_FORK: u64 = 1;
+pub const SYS_EXIT: u64 = 2;
+pub const SYS_WAIT: u64 = 3;
+pub const SYS_KILL: u64 = 6;
+pub const SYS_SBRK: u64 = 12;
+pub const SYS_MEMINFO: u64 = 22;
+pub const SYS_MMAP: u64 = 23;
+pub const SYS_MUNMAP: u64 = 24;
+pub const SYS_PGACCESS: u64 = 25;
+pub const SYS_SHMAT: u64 = 26;
+pub const SYS_SHMDT: u64 = 27;
+pub const SYS_SETTICKETS: u64 = 28;
+pub const SYS_SCHED_SETAFFINITY: u64 = 29;
+
+/// Fetch the nth 64-bit system call argument
+/// from the current process's trapframe.
+fn argraw(n: usize) -> u64 {
+    let p = CPUS.myproc().unwrap();
+    let inner = p.inner();
+    let tf = inner.trapframe.as_ref().unwrap();
+    match n {
+        0 => tf.a0,
+        1 => tf.a1,
+        2 => tf.a2,
+        3 => tf.a3,
+        4 => tf.a4,
+        5 => tf.a5,
+        _ => panic!("argraw"),
+    }
+}
+
+/// Fetch the nth 32-bit system call argument.
+pub fn argint(n: usize) -> i32 {
+    argraw(n) as i32
+}
+
+/// Retrieve an argument as a pointer.
+/// Doesn't check for legality, since
+/// copyin/copyout will do that.
+pub fn argaddr(n: usize) -> u64 {
+    argraw(n)
+}
+
+pub fn syscall() {
+    let p = CPUS.myproc().unwrap();
+    let num = p.inner().trapframe.as_ref().unwrap().a7;
+
+    // the handlers fetch their own arguments, so the
+    // trapframe must not stay borrowed while they run.
+    let ret = match num {
+        SYS_FORK => sys_fork(),
+        SYS_EXIT => sys_exit(),
+        SYS_WAIT => sys_wait(),
+        SYS_KILL => sys_kill(),
+        SYS_SBRK => sys_sbrk(),
+        SYS_MEMINFO => sys_meminfo(),
+        SYS_MMAP => sys_mmap(),
+        SYS_MUNMAP => sys_munmap(),
+        SYS_PGACCESS => sys_pgaccess(),
+        SYS_SHMAT => sys_shmat(),
+        SYS_SHMDT => sys_shmdt(),
+        SYS_SETTICKETS => sys_settickets(),
+        SYS_SCHED_SETAFFINITY => sys_sched_setaffinity(),
+        _ => {
+            println!("{} {}: unknown sys call {}", p.pid(), p.name(), num);
+            u64::MAX
+        }
+    };
+
+    p.inner().trapframe.as_mut().unwrap().a0 = ret;
+}
@@ -0,0 +1,214 @@
+//! Process and memory related system calls.
+
+use crate::{
+    kalloc::{self, MemInfo},
+    mmap::Backing,
+    param::MAXPGACCESS,
+    proc::{CPUS, PROCS},
+    shm::shm_get,
+    syscall::{argaddr, argint},
+    uaccess::{UserPtr, UserSlice},
+    vm::VirtAddr,
+};
+
+/// Exit with status n, to be collected by the parent's wait().
+pub fn sys_exit() -> u64 {
+    let n = argint(0);
+    PROCS.exit(n)
+}
+
+/// Create a child process, returning its pid,
+/// or 0 in the child.
+pub fn sys_fork() -> u64 {
+    match PROCS.retry_oom(|| PROCS.fork()) {
+        Ok(pid) => pid as u64,
+        Err(_) => u64::MAX,
+    }
+}
+
+/// Wait for a child to exit, returning its pid and
+/// copying its exit status to the int at the address.
+pub fn sys_wait() -> u64 {
+    let status = UserPtr::<i32>::new(argaddr(0));
+    match PROCS.wait(status) {
+        Ok(pid) => pid as u64,
+        Err(_) => u64::MAX,
+    }
+}
+
+/// Kill process pid; it exits the next time it
+/// would return to user space.
+pub fn sys_kill() -> u64 {
+    let pid = match usize::try_from(argint(0)) {
+        Ok(pid) => pid,
+        Err(_) => return u64::MAX,
+    };
+    match PROCS.kill(pid) {
+        Ok(()) => 0,
+        Err(_) => u64::MAX,
+    }
+}
+
+/// Grow or shrink user memory by n bytes, returning the old size.
+/// Growth is lazy: the new pages are only allocated when touched.
+pub fn sys_sbrk() -> u64 {
+    let n = argint(0) as i64;
+    let p = CPUS.myproc().unwrap();
+    let mut inner = p.inner();
+
+    let addr = p.sz();
+    let space = inner.pagetable.as_mut().unwrap();
+    let newsz = match addr.checked_add_signed(n) {
+        // the heap must stay clear of mmap regions and the trapframe.
+        Some(newsz) if newsz <= space.mmap_base() => newsz,
+        _ => return u64::MAX,
+    };
+
+    if newsz < addr {
+        space.dealloc(addr, newsz);
+    } else {
+        space.set_heap_end(newsz);
+    }
+    p.set_sz(newsz);
+
+    addr
+}
+
+/// Copy a snapshot of physical memory usage
+/// to the user's struct meminfo.
+pub fn sys_meminfo() -> u64 {
+    let info = UserPtr::<MemInfo>::new(argaddr(0));
+    match info.write(&kalloc::mem_info()) {
+        Ok(()) => 0,
+        Err(_) => u64::MAX,
+    }
+}
+
+/// Map len bytes of anonymous memory. The kernel picks where the
+/// region goes and returns it, so the address argument must be 0.
+/// Without a file system, mappings without MAP_ANONYMOUS fail.
+pub fn sys_mmap() -> u64 {
+    if argaddr(0) != 0 {
+        return u64::MAX;
+    }
+    let len = argaddr(1);
+    let prot = argint(2);
+    let flags = argint(3);
+
+    let p = CPUS.myproc().unwrap();
+    let mapped = PROCS.retry_oom(|| {
+        let mut inner = p.inner();
+        let space = inner.pagetable.as_mut().unwrap();
+        space.mmap(len, prot, flags, Backing::Anonymous)
+    });
+    match mapped {
+        Ok(addr) => addr,
+        Err(_) => u64::MAX,
+    }
+}
+
+/// Unmap the pages of [addr, addr+len).
+pub fn sys_munmap() -> u64 {
+    let addr = argaddr(0);
+    let len = argaddr(1);
+
+    let p = CPUS.myproc().unwrap();
+    let unmapped = PROCS.retry_oom(|| {
+        let mut inner = p.inner();
+        let space = inner.pagetable.as_mut().unwrap();
+        space.munmap(addr, len)
+    });
+    match unmapped {
+        Ok(()) => 0,
+        Err(_) => u64::MAX,
+    }
+}
+
+/// Map the shared memory segment named key, creating it with
+/// size bytes if no process has it mapped. Returns its address.
+pub fn sys_shmat() -> u64 {
+    let key = argint(0);
+    let size = argaddr(1);
+
+    let segment = match PROCS.retry_oom(|| shm_get(key, size)) {
+        Ok(segment) => segment,
+        Err(_) => return u64::MAX,
+    };
+
+    let p = CPUS.myproc().unwrap();
+    let attached = PROCS.retry_oom(|| {
+        let mut inner = p.inner();
+        let space = inner.pagetable.as_mut().unwrap();
+        space.shmat(segment.clone())
+    });
+    match attached {
+        Ok(addr) => addr,
+        Err(_) => u64::MAX,
+    }
+}
+
+/// Unmap the shared memory segment at addr. The segment is freed
+/// when its last mapper detaches or exits.
+pub fn sys_shmdt() -> u64 {
+    let addr = argaddr(0);
+
+    let p = CPUS.myproc().unwrap();
+    let detached = PROCS.retry_oom(|| {
+        let mut inner = p.inner();
+        let space = inner.pagetable.as_mut().unwrap();
+        space.shmdt(addr)
+    });
+    match detached {
+        Ok(()) => 0,
+        Err(_) => u64::MAX,
+    }
+}
+
+/// Set the calling process's tickets, its share of the CPU
+/// under the lottery and stride policies.
+pub fn sys_settickets() -> u64 {
+    let tickets = match usize::try_from(argint(0)) {
+        Ok(tickets) => tickets,
+        Err(_) => return u64::MAX,
+    };
+    match PROCS.set_tickets(tickets) {
+        Ok(()) => 0,
+        Err(_) => u64::MAX,
+    }
+}
+
+/// Restrict process pid, or the caller if pid is 0, to the
+/// harts set in mask.
+pub fn sys_sched_setaffinity() -> u64 {
+    let pid = match usize::try_from(argint(0)) {
+        Ok(pid) => pid,
+        Err(_) => return u64::MAX,
+    };
+    let mask = argaddr(1);
+    match PROCS.set_affinity(pid, mask) {
+        Ok(()) => 0,
+        Err(_) => u64::MAX,
+    }
+}
+
+/// Report which of npages user pages starting at va have been
+/// accessed since the last call, as a bitmask copied out to the
+/// user's buffer, one bit per page.
+pub fn sys_pgaccess() -> u64 {
+    let va = VirtAddr::new(argaddr(0));
+    let npages = argaddr(1);
+    if npages > MAXPGACCESS {
+        return u64::MAX;
+    }
+    let mask = UserSlice::new(argaddr(2), ((npages + 7) / 8) as usize);
+
+    let p = CPUS.myproc().unwrap();
+    let bits = match p.inner().pagetable.as_mut().unwrap().pgaccess(va, npages) {
+        Ok(bits) => bits,
+        Err(_) => return u64::MAX,
+    };
+    match mask.write(&bits) {
+        Ok(()) => 0,
+        Err(_) => u64::MAX,
+    }
+}
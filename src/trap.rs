@@ -1,18 +1,166 @@
 use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::{
-    memlayout::{UART0_IRQ, VIRTIO0_IRQ},
+    memlayout::{kstack_guard, TRAMPOLINE, TRAPFRAME, UART0_IRQ, VIRTIO0_IRQ},
+    param::{BALANCEINTERVAL, KSTACKSIZE, NCPU},
     plic::{plic_claim, plic_complete},
-    print,
-    proc::{cpuid, PROCS},
+    print, println,
+    proc::{cpuid, CPUS, PROCS},
     riscv::*,
+    start::stack0_guard,
+    syscall::syscall,
     uart::uart_intr,
+    virtio_disk::virtio_disk_intr,
+    vm::{FaultError, VirtAddr},
 };
 
 static TICKS: AtomicUsize = AtomicUsize::new(0);
 
 extern "C" {
     fn kernelvec();
+    // in trampoline.S
+    fn trampoline();
+    fn uservec();
+    fn userret();
+}
+
+/// handle an interrupt, exception, or system call from user space.
+/// called from trampoline.S
+#[no_mangle]
+pub extern "C" fn usertrap() {
+    if (r_sstatus() & SSTATUS_SPP) != 0 {
+        panic!("usertrap: not from user mode");
+    }
+
+    // send interrupts and exceptions to kerneltrap(),
+    // since we're now in the kernel.
+    w_stvec(kernelvec as usize);
+
+    let p = CPUS.myproc().unwrap();
+    let mut which_dev = Trap::Unknown;
+
+    // save user program counter.
+    p.inner().trapframe.as_mut().unwrap().epc = r_sepc() as u64;
+
+    if r_scause() == 8 {
+        // system call
+
+        if p.killed() {
+            PROCS.exit(-1);
+        }
+
+        // sepc points to the ecall instruction,
+        // but we want to return to the next instruction.
+        p.inner().trapframe.as_mut().unwrap().epc += 4;
+
+        // an interrupt will change sepc, scause, and sstatus,
+        // so enable only now that we're done with those registers.
+        intr_on();
+
+        syscall();
+    } else if r_scause() == 12 || r_scause() == 13 || r_scause() == 15 {
+        // instruction, load or store page fault, perhaps on a lazily
+        // allocated heap or mmap page or a copy-on-write page.
+        let va = VirtAddr::new(r_stval() as u64);
+        let write = r_scause() == 15;
+        let result = p
+            .inner()
+            .pagetable
+            .as_mut()
+            .unwrap()
+            .handle_fault(va, write);
+        match result {
+            Ok(()) => {}
+            Err(FaultError::OutOfMemory) => match PROCS.oom_kill() {
+                // retry the access once the victim has exited;
+                // let it run meanwhile.
+                Some(pid) if pid != p.pid() => PROCS.yield_cpu(),
+                _ => p.set_killed(),
+            },
+            Err(err) => {
+                println!(
+                    "usertrap(): page fault at {:#x} pid={}: {:?}",
+                    va.as_u64(),
+                    p.pid(),
+                    err
+                );
+                p.set_killed();
+            }
+        }
+    } else {
+        which_dev = devintr();
+        if let Trap::Unknown = which_dev {
+            println!(
+                "usertrap(): unexpected scause {:#x} pid={}",
+                r_scause(),
+                p.pid()
+            );
+            println!("            sepc={:#x} stval={:#x}", r_sepc(), r_stval());
+            p.set_killed();
+        }
+    }
+
+    if p.killed() {
+        PROCS.exit(-1);
+    }
+
+    // give up the CPU if this is a timer interrupt
+    // that ends the time slice.
+    if let Trap::SoftwareInterrupt = which_dev {
+        if PROCS.tick() {
+            PROCS.yield_cpu();
+        }
+    }
+
+    usertrapret();
+}
+
+/// return to user space
+pub fn usertrapret() -> ! {
+    let p = CPUS.myproc().unwrap();
+
+    // we're about to switch the destination of traps from
+    // kerneltrap() to usertrap(), so turn off interrupts until
+    // we're back in user space, where usertrap() is correct.
+    intr_off();
+
+    // send syscalls, interrupts, and exceptions to uservec in trampoline.S
+    let trampoline_uservec = TRAMPOLINE + (uservec as u64 - trampoline as u64);
+    w_stvec(trampoline_uservec as usize);
+
+    let mut inner = p.inner();
+    let kstack = inner.kstack;
+    let satp = inner.pagetable.as_mut().unwrap().activate();
+
+    // set up trapframe values that uservec will need when
+    // the process next traps into the kernel.
+    let tf = inner.trapframe.as_mut().unwrap();
+    tf.kernel_satp = r_satp() as u64; // kernel page table
+    tf.kernel_sp = kstack + KSTACKSIZE as u64; // process's kernel stack
+    tf.kernel_trap = usertrap as u64;
+    tf.kernel_hartid = r_tp() as u64; // hartid for cpuid()
+
+    // set up the registers that trampoline.S's sret will use
+    // to get to user space.
+
+    // set S Previous Privilege mode to User.
+    let mut x = r_sstatus();
+    x &= !SSTATUS_SPP; // clear SPP to 0 for user mode
+    x |= SSTATUS_SPIE; // enable interrupts in user mode
+    w_sstatus(x);
+
+    // set S Exception Program Counter to the saved user pc.
+    w_sepc(tf.epc as usize);
+    drop(inner);
+
+    // jump to userret in trampoline.S at the top of memory, which
+    // switches to the user page table, restores user registers,
+    // and switches to user mode with sret.
+    let trampoline_userret = TRAMPOLINE + (userret as u64 - trampoline as u64);
+    unsafe {
+        let userret: extern "C" fn(u64, u64) -> ! = core::mem::transmute(trampoline_userret);
+        userret(TRAPFRAME, satp);
+    }
 }
 
 // interrupts and exceptions from kernel code go here via kernelvec,
@@ -32,7 +180,13 @@ pub extern "C" fn kerneltrap() {
 
     match devintr() {
         Trap::Unknown => panic!("kerneltrap: unknown trap"),
-        Trap::SoftwareInterrupt => {}
+        // give up the CPU if this is a timer interrupt that
+        // ends the time slice of a running process.
+        Trap::SoftwareInterrupt => {
+            if PROCS.tick() {
+                PROCS.yield_cpu();
+            }
+        }
         Trap::ExternalInterrupt => {}
     }
 
@@ -42,6 +196,38 @@ pub extern "C" fn kerneltrap() {
     w_sstatus(sstatus);
 }
 
+// a stack per hart for kernelfault(), since the
+// stack that faulted may be what went wrong.
+#[export_name = "fault_stack"]
+static mut FAULT_STACK: [[u8; KSTACKSIZE]; NCPU] = [[0; KSTACKSIZE]; NCPU];
+
+// page faults from kernel code go here via kernelvec,
+// on this hart's fault stack. there are no legitimate
+// ones, but a fault in a guard page is worth naming.
+#[no_mangle]
+pub extern "C" fn kernelfault() -> ! {
+    let va = r_stval() as u64;
+    let guard = |page: u64| page <= va && va < page + PGSIZE;
+
+    if kstack_guard(va).is_some() || (0..NCPU).any(|hart| guard(stack0_guard(hart))) {
+        match CPUS.myproc() {
+            Some(p) => panic!(
+                "kernel stack overflow in pid {} / hart {}",
+                p.pid(),
+                cpuid()
+            ),
+            None => panic!("kernel stack overflow in pid - / hart {}", cpuid()),
+        }
+    }
+
+    panic!(
+        "kerneltrap: page fault at {:#x} sepc={:#x} scause={:#x}",
+        va,
+        r_sepc(),
+        r_scause()
+    );
+}
+
 // check if it's an external interrupt or software interrupt,
 // and handle it.
 // returns 2 if timer interrupt,
@@ -63,7 +249,8 @@ fn devintr() -> Trap {
             }
             VIRTIO0_IRQ => {
                 // this is a virtio interrupt.
-                // virtio_disk_intr();
+                virtio_disk_intr();
+                plic_complete(irq);
             }
             _ => {
                 // unknown interrupt
@@ -101,8 +288,11 @@ fn devintr() -> Trap {
 
 fn clock_intr() {
     // increment the number of ticks.
-    TICKS.fetch_add(1, Ordering::Relaxed);
+    let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
     PROCS.wakeup(&TICKS as *const _ as usize);
+    if ticks % BALANCEINTERVAL == 0 {
+        PROCS.balance();
+    }
     print!(".");
 }
 
@@ -0,0 +1,129 @@
+//! Checked access to the current process's user memory.
+//!
+//! System calls and device drivers get user addresses as [`UserPtr`]
+//! and [`UserSlice`] rather than raw integers. Every access goes through
+//! the process's page table, so a bad address fails with
+//! [`CopyError::BadAddress`] instead of faulting in the kernel.
+
+use core::{marker::PhantomData, mem, slice};
+
+use crate::{
+    proc::{CPUS, PROCS},
+    vm::{AddressSpace, CopyError, VirtAddr},
+};
+
+/// Run f on the current process's address space. Faulting pages in
+/// may run out of memory, in which case the OOM killer makes room.
+fn with_space<R>(
+    mut f: impl FnMut(&mut AddressSpace) -> Result<R, CopyError>,
+) -> Result<R, CopyError> {
+    let p = CPUS.myproc().ok_or(CopyError::BadAddress)?;
+    PROCS.retry_oom(|| {
+        let mut inner = p.inner();
+        let space = inner.pagetable.as_mut().ok_or(CopyError::BadAddress)?;
+        f(space)
+    })
+}
+
+/// The address of a T in user memory.
+pub struct UserPtr<T> {
+    addr: u64,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T: Copy> UserPtr<T> {
+    pub fn new(addr: u64) -> Self {
+        UserPtr {
+            addr,
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    #[inline]
+    pub fn is_null(&self) -> bool {
+        self.addr == 0
+    }
+
+    /// Copy the value in from user memory.
+    pub fn read(&self) -> Result<T, CopyError> {
+        let mut value = mem::MaybeUninit::<T>::uninit();
+        let dst = unsafe {
+            slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, mem::size_of::<T>())
+        };
+        with_space(|space| space.copy_in(dst, VirtAddr::new(self.addr)))?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Copy value out to user memory.
+    pub fn write(&self, value: &T) -> Result<(), CopyError> {
+        let src =
+            unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) };
+        with_space(|space| space.copy_out(VirtAddr::new(self.addr), src))
+    }
+}
+
+/// A range of bytes in user memory.
+#[derive(Clone, Copy)]
+pub struct UserSlice {
+    addr: u64,
+    len: usize,
+}
+
+impl UserSlice {
+    pub fn new(addr: u64, len: usize) -> Self {
+        UserSlice { addr, len }
+    }
+
+    #[inline]
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The part of the slice from offset on.
+    pub fn skip(&self, offset: usize) -> UserSlice {
+        let offset = offset.min(self.len);
+        UserSlice::new(self.addr + offset as u64, self.len - offset)
+    }
+
+    /// Copy the start of the slice into dst, which must not be longer.
+    pub fn read(&self, dst: &mut [u8]) -> Result<(), CopyError> {
+        assert!(dst.len() <= self.len, "UserSlice::read: too long");
+        with_space(|space| space.copy_in(dst, VirtAddr::new(self.addr)))
+    }
+
+    /// Copy src to the start of the slice, which must be at least as long.
+    pub fn write(&self, src: &[u8]) -> Result<(), CopyError> {
+        assert!(src.len() <= self.len, "UserSlice::write: too long");
+        with_space(|space| space.copy_out(VirtAddr::new(self.addr), src))
+    }
+
+    /// Copy a null-terminated string at the start of the slice into
+    /// dst. Returns the length of the string.
+    pub fn read_str(&self, dst: &mut [u8]) -> Result<usize, CopyError> {
+        let n = dst.len().min(self.len);
+        with_space(|space| space.copy_in_str(&mut dst[..n], VirtAddr::new(self.addr)))
+    }
+}
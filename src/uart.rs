@@ -74,7 +74,7 @@ impl SpinMutex<Uart> {
     /// because it may block, it can't be called
     /// from interrupts; it's only suitable for use
     /// by write().
-    pub fn uart_putc(&mut self, c: u8) {
+    pub fn uart_putc(&self, c: u8) {
         let mut lock = self.lock();
 
         if PANICKED.load(Ordering::Relaxed) {
@@ -0,0 +1,487 @@
+//! driver for qemu's virtio disk device.
+//! uses qemu's mmio interface to virtio.
+//!
+//! qemu ... -drive file=fs.img,if=none,format=raw,id=x0 -device virtio-blk-device,drive=x0,bus=virtio-mmio-bus.0
+//!
+//! The caller of virtio_disk_rw() sleeps until virtio_disk_intr()
+//! sees the device has finished its request. At boot there is no
+//! process to sleep, so it polls the used ring instead.
+
+use core::{
+    ptr,
+    sync::atomic::{fence, Ordering},
+};
+
+use crate::{
+    kalloc::{kalloc, MemTag},
+    memlayout::VIRTIO0,
+    println,
+    proc::{CPUS, PROCS},
+    riscv::PGSIZE,
+    spinlock::{SpinMutex, SpinMutexGuard},
+    vm::PhysAddr,
+};
+
+// virtio mmio control registers, mapped starting at 0x10001000.
+// from qemu virtio_mmio.h
+const VIRTIO_MMIO_MAGIC_VALUE: usize = 0x000; // 0x74726976
+const VIRTIO_MMIO_VERSION: usize = 0x004; // version; should be 2
+const VIRTIO_MMIO_DEVICE_ID: usize = 0x008; // device type; 1 is net, 2 is disk
+const VIRTIO_MMIO_VENDOR_ID: usize = 0x00c; // 0x554d4551
+const VIRTIO_MMIO_DEVICE_FEATURES: usize = 0x010;
+const VIRTIO_MMIO_DRIVER_FEATURES: usize = 0x020;
+const VIRTIO_MMIO_QUEUE_SEL: usize = 0x030; // select queue, write-only
+const VIRTIO_MMIO_QUEUE_NUM_MAX: usize = 0x034; // max size of current queue, read-only
+const VIRTIO_MMIO_QUEUE_NUM: usize = 0x038; // size of current queue, write-only
+const VIRTIO_MMIO_QUEUE_READY: usize = 0x044; // ready bit
+const VIRTIO_MMIO_QUEUE_NOTIFY: usize = 0x050; // write-only
+const VIRTIO_MMIO_INTERRUPT_STATUS: usize = 0x060; // read-only
+const VIRTIO_MMIO_INTERRUPT_ACK: usize = 0x064; // write-only
+const VIRTIO_MMIO_STATUS: usize = 0x070; // read/write
+const VIRTIO_MMIO_QUEUE_DESC_LOW: usize = 0x080; // physical address for descriptor table, write-only
+const VIRTIO_MMIO_QUEUE_DESC_HIGH: usize = 0x084;
+const VIRTIO_MMIO_DRIVER_DESC_LOW: usize = 0x090; // physical address for available ring, write-only
+const VIRTIO_MMIO_DRIVER_DESC_HIGH: usize = 0x094;
+const VIRTIO_MMIO_DEVICE_DESC_LOW: usize = 0x0a0; // physical address for used ring, write-only
+const VIRTIO_MMIO_DEVICE_DESC_HIGH: usize = 0x0a4;
+
+// status register bits, from qemu virtio_config.h
+const VIRTIO_CONFIG_S_ACKNOWLEDGE: u32 = 1;
+const VIRTIO_CONFIG_S_DRIVER: u32 = 2;
+const VIRTIO_CONFIG_S_DRIVER_OK: u32 = 4;
+const VIRTIO_CONFIG_S_FEATURES_OK: u32 = 8;
+
+// device feature bits
+const VIRTIO_BLK_F_RO: u32 = 5; // Disk is read-only
+const VIRTIO_BLK_F_SCSI: u32 = 7; // Supports scsi command passthru
+const VIRTIO_BLK_F_CONFIG_WCE: u32 = 11; // Writeback mode available in config
+const VIRTIO_BLK_F_MQ: u32 = 12; // support more than one vq
+const VIRTIO_F_ANY_LAYOUT: u32 = 27;
+const VIRTIO_RING_F_INDIRECT_DESC: u32 = 28;
+const VIRTIO_RING_F_EVENT_IDX: u32 = 29;
+
+// this many virtio descriptors.
+// must be a power of two.
+const NUM: usize = 8;
+
+// a single descriptor, from the spec.
+#[repr(C)]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+const VRING_DESC_F_NEXT: u16 = 1; // chained with another descriptor
+const VRING_DESC_F_WRITE: u16 = 2; // device writes (vs read)
+
+// the (entire) avail ring, from the spec.
+#[repr(C)]
+struct VirtqAvail {
+    flags: u16,       // always zero
+    idx: u16,         // driver will write ring[idx] next
+    ring: [u16; NUM], // descriptor numbers of chain heads
+    unused: u16,
+}
+
+// one entry in the "used" ring, with which the
+// device tells the driver about completed requests.
+#[repr(C)]
+struct VirtqUsedElem {
+    id: u32, // index of start of completed descriptor chain
+    len: u32,
+}
+
+#[repr(C)]
+struct VirtqUsed {
+    flags: u16, // always zero
+    idx: u16,   // device increments when it adds a ring[] entry
+    ring: [VirtqUsedElem; NUM],
+}
+
+// these are specific to virtio block devices, e.g. disks,
+// described in Section 5.2 of the spec.
+const VIRTIO_BLK_T_IN: u32 = 0; // read the disk
+const VIRTIO_BLK_T_OUT: u32 = 1; // write the disk
+
+// the format of the first descriptor in a disk request.
+// to be followed by two more descriptors containing
+// the block, and a one-byte status.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct VirtioBlkReq {
+    r#type: u32, // VIRTIO_BLK_T_IN or ..._OUT
+    reserved: u32,
+    sector: u64,
+}
+
+/// Bytes per disk sector.
+pub const SECTOR_SIZE: usize = 512;
+
+static DISK: SpinMutex<Disk> = SpinMutex::new("virtio_disk", Disk::new());
+
+struct Disk {
+    // the virtio driver and device mostly communicate through a set of
+    // structures in RAM, each in a page of its own. Null until
+    // virtio_disk_init() finds a disk.
+    desc: *mut VirtqDesc,
+    avail: *mut VirtqAvail,
+    used: *mut VirtqUsed,
+
+    // our own book-keeping.
+    free: [bool; NUM], // is a descriptor free?
+    used_idx: u16,     // we've looked this far in used.ring.
+
+    // track info about in-flight operations,
+    // for use when completion interrupt arrives.
+    // indexed by first descriptor index of chain.
+    info: [Info; NUM],
+
+    // disk command headers.
+    // one-for-one with descriptors, for convenience.
+    // the kernel's data is direct-mapped, so their addresses are
+    // also the physical ones the device needs.
+    ops: [VirtioBlkReq; NUM],
+}
+
+#[derive(Clone, Copy)]
+struct Info {
+    status: u8, // device writes 0 on success
+    done: bool, // set by complete()
+}
+
+// the ring pointers only point into pages the disk owns.
+unsafe impl Send for Disk {}
+
+impl Disk {
+    const fn new() -> Self {
+        Disk {
+            desc: ptr::null_mut(),
+            avail: ptr::null_mut(),
+            used: ptr::null_mut(),
+            free: [false; NUM],
+            used_idx: 0,
+            info: [Info {
+                status: 0,
+                done: false,
+            }; NUM],
+            ops: [VirtioBlkReq {
+                r#type: 0,
+                reserved: 0,
+                sector: 0,
+            }; NUM],
+        }
+    }
+
+    /// find a free descriptor, mark it non-free, return its index.
+    fn alloc_desc(&mut self) -> Option<usize> {
+        let i = self.free.iter().position(|&free| free)?;
+        self.free[i] = false;
+        Some(i)
+    }
+
+    /// mark a descriptor as free.
+    fn free_desc(&mut self, i: usize) {
+        assert!(!self.free[i], "free_desc: already free");
+        unsafe {
+            self.desc.add(i).write(VirtqDesc {
+                addr: 0,
+                len: 0,
+                flags: 0,
+                next: 0,
+            });
+        }
+        self.free[i] = true;
+        PROCS.wakeup(ptr::addr_of!(self.free) as usize);
+    }
+
+    /// free a chain of descriptors.
+    fn free_chain(&mut self, mut i: usize) {
+        loop {
+            let desc = unsafe { self.desc.add(i).read() };
+            self.free_desc(i);
+            if desc.flags & VRING_DESC_F_NEXT == 0 {
+                break;
+            }
+            i = desc.next as usize;
+        }
+    }
+
+    /// allocate three descriptors (they need not be contiguous).
+    /// disk transfers always use three descriptors.
+    fn alloc3_desc(&mut self) -> Option<[usize; 3]> {
+        let mut idx = [0; 3];
+        for i in 0..3 {
+            match self.alloc_desc() {
+                Some(d) => idx[i] = d,
+                None => {
+                    for &d in &idx[..i] {
+                        self.free_desc(d);
+                    }
+                    return None;
+                }
+            }
+        }
+        Some(idx)
+    }
+
+    /// Mark the requests the device has put on the used ring as
+    /// done, and wake up their owners.
+    fn complete(&mut self) {
+        // the device increments used.idx when it
+        // adds an entry to the used ring.
+        let used = self.used;
+        while self.used_idx != unsafe { ptr::read_volatile(ptr::addr_of!((*used).idx)) } {
+            fence(Ordering::SeqCst);
+            let slot = self.used_idx as usize % NUM;
+            let id = unsafe { ptr::read_volatile(ptr::addr_of!((*used).ring[slot].id)) } as usize;
+
+            self.info[id].done = true; // disk is done with the request
+            PROCS.wakeup(ptr::addr_of!(self.info[id]) as usize);
+
+            self.used_idx = self.used_idx.wrapping_add(1);
+        }
+    }
+}
+
+/// Wait for virtio_disk_intr() to wake chan, releasing the disk
+/// while asleep. With no process to sleep, poll for finished
+/// requests instead; the caller checks again either way.
+fn disk_wait(disk: &mut SpinMutexGuard<'_, Disk>, chan: usize) {
+    if CPUS.myproc().is_some() {
+        PROCS.sleep(chan, disk);
+    } else {
+        disk.complete();
+        core::hint::spin_loop();
+    }
+}
+
+#[inline]
+fn read_reg(r: usize) -> u32 {
+    unsafe { ptr::read_volatile((VIRTIO0 as usize + r) as *const u32) }
+}
+
+#[inline]
+fn write_reg(r: usize, value: u32) {
+    unsafe { ptr::write_volatile((VIRTIO0 as usize + r) as *mut u32, value) }
+}
+
+/// Set up the disk, if qemu was given one.
+pub fn virtio_disk_init() {
+    if read_reg(VIRTIO_MMIO_MAGIC_VALUE) != 0x74726976
+        || read_reg(VIRTIO_MMIO_VERSION) != 2
+        || read_reg(VIRTIO_MMIO_DEVICE_ID) != 2
+        || read_reg(VIRTIO_MMIO_VENDOR_ID) != 0x554d4551
+    {
+        println!("virtio disk: not found");
+        return;
+    }
+
+    let mut disk = DISK.lock();
+    let mut status = 0;
+
+    // reset device
+    write_reg(VIRTIO_MMIO_STATUS, status);
+
+    // set ACKNOWLEDGE status bit
+    status |= VIRTIO_CONFIG_S_ACKNOWLEDGE;
+    write_reg(VIRTIO_MMIO_STATUS, status);
+
+    // set DRIVER status bit
+    status |= VIRTIO_CONFIG_S_DRIVER;
+    write_reg(VIRTIO_MMIO_STATUS, status);
+
+    // negotiate features
+    let mut features = read_reg(VIRTIO_MMIO_DEVICE_FEATURES);
+    for bit in [
+        VIRTIO_BLK_F_RO,
+        VIRTIO_BLK_F_SCSI,
+        VIRTIO_BLK_F_CONFIG_WCE,
+        VIRTIO_BLK_F_MQ,
+        VIRTIO_F_ANY_LAYOUT,
+        VIRTIO_RING_F_EVENT_IDX,
+        VIRTIO_RING_F_INDIRECT_DESC,
+    ] {
+        features &= !(1 << bit);
+    }
+    write_reg(VIRTIO_MMIO_DRIVER_FEATURES, features);
+
+    // tell device that feature negotiation is complete.
+    status |= VIRTIO_CONFIG_S_FEATURES_OK;
+    write_reg(VIRTIO_MMIO_STATUS, status);
+
+    // re-read status to ensure FEATURES_OK is set.
+    if read_reg(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_FEATURES_OK == 0 {
+        panic!("virtio disk FEATURES_OK unset");
+    }
+
+    // initialize queue 0.
+    write_reg(VIRTIO_MMIO_QUEUE_SEL, 0);
+
+    // ensure queue 0 is not in use.
+    if read_reg(VIRTIO_MMIO_QUEUE_READY) != 0 {
+        panic!("virtio disk should not be ready");
+    }
+
+    // check maximum queue size.
+    let max = read_reg(VIRTIO_MMIO_QUEUE_NUM_MAX);
+    if max == 0 {
+        panic!("virtio disk has no queue 0");
+    }
+    if (max as usize) < NUM {
+        panic!("virtio disk max queue too short");
+    }
+
+    // allocate and zero queue memory.
+    let page = || {
+        let frame = kalloc(MemTag::Other).expect("virtio disk kalloc");
+        unsafe {
+            ptr::write_bytes(frame.as_mut_ptr::<u8>(), 0, PGSIZE as usize);
+        }
+        frame.into_start_address()
+    };
+    let (desc, avail, used) = (page(), page(), page());
+    disk.desc = desc.as_u64() as *mut VirtqDesc;
+    disk.avail = avail.as_u64() as *mut VirtqAvail;
+    disk.used = used.as_u64() as *mut VirtqUsed;
+
+    // all NUM descriptors start out unused.
+    disk.free = [true; NUM];
+
+    // set queue size.
+    write_reg(VIRTIO_MMIO_QUEUE_NUM, NUM as u32);
+
+    // write physical addresses.
+    write_reg(VIRTIO_MMIO_QUEUE_DESC_LOW, desc.as_u64() as u32);
+    write_reg(VIRTIO_MMIO_QUEUE_DESC_HIGH, (desc.as_u64() >> 32) as u32);
+    write_reg(VIRTIO_MMIO_DRIVER_DESC_LOW, avail.as_u64() as u32);
+    write_reg(VIRTIO_MMIO_DRIVER_DESC_HIGH, (avail.as_u64() >> 32) as u32);
+    write_reg(VIRTIO_MMIO_DEVICE_DESC_LOW, used.as_u64() as u32);
+    write_reg(VIRTIO_MMIO_DEVICE_DESC_HIGH, (used.as_u64() >> 32) as u32);
+
+    // queue is ready.
+    write_reg(VIRTIO_MMIO_QUEUE_READY, 0x1);
+
+    // tell device we're completely ready.
+    status |= VIRTIO_CONFIG_S_DRIVER_OK;
+    write_reg(VIRTIO_MMIO_STATUS, status);
+}
+
+/// Whether virtio_disk_init() found a disk.
+pub fn virtio_disk_present() -> bool {
+    !DISK.lock().desc.is_null()
+}
+
+/// Read or write len bytes of physical memory at pa from or to
+/// the disk, starting at sector. len must be a multiple of
+/// SECTOR_SIZE. Returns once the device is done; must not be
+/// called holding a spinlock, since it may sleep.
+pub fn virtio_disk_rw(pa: PhysAddr, len: usize, sector: u64, write: bool) {
+    assert!(len % SECTOR_SIZE == 0, "virtio_disk_rw: partial sector");
+
+    let mut disk = DISK.lock();
+    if disk.desc.is_null() {
+        panic!("virtio_disk_rw: no disk");
+    }
+
+    // the spec's Section 5.2 says that legacy block operations use
+    // three descriptors: one for type/reserved/sector, one for the
+    // data, one for a 1-byte status result.
+
+    // allocate the three descriptors.
+    let idx = loop {
+        if let Some(idx) = disk.alloc3_desc() {
+            break idx;
+        }
+        let chan = ptr::addr_of!(disk.free) as usize;
+        disk_wait(&mut disk, chan);
+    };
+
+    // format the three descriptors.
+    disk.ops[idx[0]] = VirtioBlkReq {
+        r#type: if write {
+            VIRTIO_BLK_T_OUT // write the disk
+        } else {
+            VIRTIO_BLK_T_IN // read the disk
+        },
+        reserved: 0,
+        sector,
+    };
+    disk.info[idx[0]] = Info {
+        status: 0xff, // device writes 0 on success
+        done: false,
+    };
+
+    let op = ptr::addr_of!(disk.ops[idx[0]]) as u64;
+    let status = ptr::addr_of!(disk.info[idx[0]].status) as u64;
+    unsafe {
+        let desc = disk.desc;
+        desc.add(idx[0]).write(VirtqDesc {
+            addr: op,
+            len: core::mem::size_of::<VirtioBlkReq>() as u32,
+            flags: VRING_DESC_F_NEXT,
+            next: idx[1] as u16,
+        });
+        desc.add(idx[1]).write(VirtqDesc {
+            addr: pa.as_u64(),
+            len: len as u32,
+            // device reads the memory for a write, writes it for a read.
+            flags: if write { 0 } else { VRING_DESC_F_WRITE } | VRING_DESC_F_NEXT,
+            next: idx[2] as u16,
+        });
+        desc.add(idx[2]).write(VirtqDesc {
+            addr: status,
+            len: 1,
+            flags: VRING_DESC_F_WRITE, // device writes the status
+            next: 0,
+        });
+
+        // tell the device the first index in our chain of descriptors.
+        let avail = &mut *disk.avail;
+        let i = ptr::read_volatile(ptr::addr_of!(avail.idx));
+        avail.ring[i as usize % NUM] = idx[0] as u16;
+
+        fence(Ordering::SeqCst);
+
+        // tell the device another avail ring entry is available.
+        ptr::write_volatile(ptr::addr_of_mut!(avail.idx), i.wrapping_add(1));
+
+        fence(Ordering::SeqCst);
+    }
+
+    write_reg(VIRTIO_MMIO_QUEUE_NOTIFY, 0); // value is queue number
+
+    // wait for virtio_disk_intr() to say request has finished.
+    let chan = ptr::addr_of!(disk.info[idx[0]]) as usize;
+    while !disk.info[idx[0]].done {
+        disk_wait(&mut disk, chan);
+    }
+
+    let status = unsafe { ptr::read_volatile(ptr::addr_of!(disk.info[idx[0]].status)) };
+    if status != 0 {
+        panic!("virtio_disk_rw: status {}", status);
+    }
+
+    disk.free_chain(idx[0]);
+}
+
+/// The device interrupts when it has finished requests:
+/// mark them done and wake up whoever is waiting for them.
+pub fn virtio_disk_intr() {
+    let mut disk = DISK.lock();
+
+    // the device won't raise another interrupt until we tell it
+    // we've seen this interrupt, which the following line does.
+    // this may race with the device writing new entries to
+    // the "used" ring, in which case we may process the new
+    // completion entries in this interrupt, and have nothing to do
+    // in the next interrupt, which is harmless.
+    let status = read_reg(VIRTIO_MMIO_INTERRUPT_STATUS);
+    write_reg(VIRTIO_MMIO_INTERRUPT_ACK, status & 0x3);
+
+    fence(Ordering::SeqCst);
+
+    if !disk.used.is_null() {
+        disk.complete();
+    }
+}
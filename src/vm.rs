@@ -1,16 +1,33 @@
+use alloc::{sync::Arc, vec, vec::Vec};
 use bitflags::bitflags;
 use core::{
     cell::UnsafeCell,
+    fmt::{self, Write},
     ops::{Add, AddAssign, Index, IndexMut},
     ptr::{self, NonNull},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use crate::{
-    memlayout::{KERNBASE, PHYSTOP, PLIC, TRAMPOLINE, UART0, VIRTIO0},
-    proc::proc_mapstacks,
+    error::KernelError,
+    kalloc::{kalloc, kfree, page_get, page_refs, MemTag, PhysFrame},
+    memlayout::{KERNBASE, PHYSTOP, PLIC, TRAMPOLINE, TRAPFRAME, UART0, VIRTIO0},
+    mmap::{
+        Backing, MmapError, Vma, MAP_ANONYMOUS, MAP_PRIVATE, MAP_SHARED, PROT_NONE, PROT_READ,
+        PROT_WRITE,
+    },
+    param::NCPU,
+    print, println,
+    proc::{cpuid, nkstack, try_proc_mapstacks, CPUS, PROCS},
     riscv::{
-        make_satp, pa2pte, pg_index, pg_round_down, pte2pa, sfence_vma, w_satp, MAXVA, PGSIZE,
+        make_satp, pa2pte, pg_index, pg_round_down, pg_round_up, pte2pa, r_satp, sfence_vma,
+        sfence_vma_asid, sfence_vma_page, w_satp, MAXVA, PGSHIFT, PGSIZE, PTLEVELS, SATP_ASID_MASK,
+        SATP_ASID_SHIFT,
     },
+    shm::Segment,
+    spinlock::SpinMutex,
+    start::stack0_guard,
+    swap::{swap_alloc, swap_enabled, swap_free, swap_read},
 };
 
 /// The kernel's page table.
@@ -18,13 +35,116 @@ static KERNEL_PAGE_TABLE: PageTablePtr = PageTablePtr::dangling();
 
 extern "C" {
     static etext: [u8; 0]; // kernel.ld sets this to end of kernel code.
+    static erodata: [u8; 0]; // end of read-only data.
+    static sdata: [u8; 0]; // start of data and bss.
     static trampoline: [u8; 0]; // trampoline.S
 }
 
 // Initialize the one kernel_pagetable
 pub fn kvminit() {
+    let page_table = unsafe { kvmmake() };
+
+    // W^X: no kernel page may be both writable and executable.
+    for mapping in unsafe { page_table.as_ref() }.mappings() {
+        assert!(
+            !mapping
+                .flags
+                .contains(PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTABLE),
+            "kvminit: {:#x} is writable and executable",
+            mapping.va.as_u64()
+        );
+    }
+
+    let (pages, small_pages) = unsafe { page_table_pages(page_table.as_ref()) };
+    println!(
+        "kvminit: {} page-table pages ({} with 4 KiB pages only)",
+        pages, small_pages
+    );
+
+    #[cfg(feature = "vmprint")]
+    vmprint(unsafe { page_table.as_ref() });
+
     unsafe {
-        KERNEL_PAGE_TABLE.init(kvmmake());
+        KERNEL_PAGE_TABLE.init(page_table);
+    }
+}
+
+/// Print the contents of a page table, one line per valid PTE,
+/// indented by depth. Each line shows the PTE index, the PTE, the
+/// physical address it points to, and for leaves the flags.
+pub fn vmprint(page_table: &PageTable) {
+    println!("page table {:#x}", page_table as *const PageTable as u64);
+    vmprint_level(page_table, PTLEVELS - 1);
+}
+
+fn vmprint_level(page_table: &PageTable, level: u8) {
+    for (i, pte) in page_table.entries.iter().enumerate() {
+        if !pte.flags().contains(PageTableEntryFlags::VALID) {
+            continue;
+        }
+        for _ in level..PTLEVELS {
+            print!(" ..");
+        }
+        print!(
+            "{}: pte {:#x} pa {:#x}",
+            i,
+            pte.as_u64(),
+            pte.addr().as_u64()
+        );
+        if pte.is_leaf() {
+            println!(" {}", FlagNames(pte.flags()));
+        } else {
+            println!();
+            let next = unsafe { &*(pte.addr().as_u64() as *const PageTable) };
+            vmprint_level(next, level - 1);
+        }
+    }
+}
+
+/// Shows PTE flags as "rwxugad", with '-' for the ones not set.
+struct FlagNames(PageTableEntryFlags);
+
+impl fmt::Display for FlagNames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (PageTableEntryFlags::READABLE, 'r'),
+            (PageTableEntryFlags::WRITABLE, 'w'),
+            (PageTableEntryFlags::EXECUTABLE, 'x'),
+            (PageTableEntryFlags::USER, 'u'),
+            (PageTableEntryFlags::GLOBAL, 'g'),
+            (PageTableEntryFlags::ACCESSED, 'a'),
+            (PageTableEntryFlags::DIRTY, 'd'),
+        ];
+        for (flag, name) in names {
+            f.write_char(if self.0.contains(flag) { name } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+/// Count the page-table pages of a page table, and how many it
+/// would take if every megapage leaf were split into 4 KiB pages.
+fn page_table_pages(page_table: &PageTable) -> (usize, usize) {
+    let mut pages = 0;
+    let mut extra = 0;
+    count_page_tables(page_table, PTLEVELS - 1, &mut pages, &mut extra);
+    (pages, pages + extra)
+}
+
+fn count_page_tables(page_table: &PageTable, level: u8, pages: &mut usize, extra: &mut usize) {
+    *pages += 1;
+    for pte in page_table.entries.iter() {
+        if !pte.flags().contains(PageTableEntryFlags::VALID) {
+            continue;
+        }
+        if pte.is_leaf() {
+            // a 2 MiB leaf replaces one level-0 table; a 1 GiB
+            // leaf replaces a level-1 table and 512 level-0 ones.
+            *extra += (0..level as u32).map(|l| ENTRY_COUNT.pow(l)).sum::<usize>();
+        } else if level > 0 {
+            let next = unsafe { &*(pte.addr().as_u64() as *const PageTable) };
+            count_page_tables(next, level - 1, pages, extra);
+        }
     }
 }
 
@@ -53,7 +173,7 @@ pub unsafe fn kvmmake() -> NonNull<PageTable> {
         PageTableEntryFlags::READABLE | PageTableEntryFlags::WRITABLE,
     );
 
-    // PLIC
+    // PLIC, as two megapages.
     kvmmap(
         page_table,
         VirtAddr::new(PLIC),
@@ -71,14 +191,34 @@ pub unsafe fn kvmmake() -> NonNull<PageTable> {
         PageTableEntryFlags::READABLE | PageTableEntryFlags::EXECUTABLE,
     );
 
-    // map kernel data and the physical RAM we'll make use of.
-    kvmmap(
-        page_table,
-        VirtAddr::new(ptr::addr_of!(etext) as u64),
-        PhysAddr::new(ptr::addr_of!(etext) as u64),
-        PHYSTOP - ptr::addr_of!(etext) as u64,
-        PageTableEntryFlags::READABLE | PageTableEntryFlags::WRITABLE,
-    );
+    // map kernel read-only data read-only.
+    if ptr::addr_of!(erodata) as u64 > ptr::addr_of!(etext) as u64 {
+        kvmmap(
+            page_table,
+            VirtAddr::new(ptr::addr_of!(etext) as u64),
+            PhysAddr::new(ptr::addr_of!(etext) as u64),
+            ptr::addr_of!(erodata) as u64 - ptr::addr_of!(etext) as u64,
+            PageTableEntryFlags::READABLE,
+        );
+    }
+
+    // map kernel data and the physical RAM we'll make use of,
+    // leaving out the guard pages below the boot stacks.
+    // past the first 2 MiB boundary this is all megapages.
+    let mut start = ptr::addr_of!(sdata) as u64;
+    let guards = (0..NCPU).map(stack0_guard);
+    for end in guards.chain([PHYSTOP]) {
+        if end > start {
+            kvmmap(
+                page_table,
+                VirtAddr::new(start),
+                PhysAddr::new(start),
+                end - start,
+                PageTableEntryFlags::READABLE | PageTableEntryFlags::WRITABLE,
+            );
+        }
+        start = end + PGSIZE;
+    }
 
     // map the trampoline for trap entry/exit to
     // the highest virtual address in the kernel.
@@ -87,11 +227,18 @@ pub unsafe fn kvmmake() -> NonNull<PageTable> {
         VirtAddr::new(TRAMPOLINE),
         PhysAddr::new(ptr::addr_of!(trampoline) as u64),
         PGSIZE,
-        PageTableEntryFlags::READABLE | PageTableEntryFlags::WRITABLE,
+        PageTableEntryFlags::READABLE | PageTableEntryFlags::EXECUTABLE,
     );
 
-    // map kernel stacks
-    proc_mapstacks(page_table);
+    // map kernel stacks. without memory for all of them the
+    // process table is cut short rather than the boot failing.
+    if let Err(err) = try_proc_mapstacks(page_table) {
+        println!(
+            "kvmmake: {:?} mapping kernel stacks; room for {} processes",
+            err,
+            nkstack()
+        );
+    }
 
     NonNull::new(page_table).unwrap()
 }
@@ -106,13 +253,30 @@ pub fn kvmmap(
     size: u64,
     flags: PageTableEntryFlags,
 ) {
-    map_pages(page_table, va, pa, size, flags).expect("kvmmap failed");
+    try_kvmmap(page_table, va, pa, size, flags).expect("kvmmap failed");
+}
+
+/// Like kvmmap(), but return an error instead of panicking
+/// if a page-table page can't be allocated.
+pub fn try_kvmmap(
+    page_table: &mut PageTable,
+    va: VirtAddr,
+    pa: PhysAddr,
+    size: u64,
+    flags: PageTableEntryFlags,
+) -> Result<(), KernelError> {
+    map_pages(page_table, va, pa, size, flags)?;
+    Ok(())
 }
 
 /// Create PTEs for virtual addresses starting at va that refer to
 /// physical addresses starting at pa. va and size might not
 /// be page-aligned. Returns 0 on success, -1 if walk() couldn't
 /// allocate a needed page-table page.
+///
+/// Wherever va and pa are both aligned to a megapage (2 MiB) or
+/// gigapage (1 GiB) and enough of the range is left, a single
+/// leaf entry at level 1 or 2 maps the whole of it.
 fn map_pages(
     page_table: &mut PageTable,
     va: VirtAddr,
@@ -123,11 +287,20 @@ fn map_pages(
     assert!(size > 0, "map_pages: size must be > 0");
 
     let mut a = pg_round_down(va.as_u64());
-    let last = pg_round_down(va.as_u64() + size - 1);
+    let end = pg_round_down(va.as_u64() + size - 1) + PGSIZE;
+
+    while a < end {
+        let level = (0..PTLEVELS)
+            .rev()
+            .find(|&level| {
+                let page_size = level_size(level);
+                a % page_size == 0 && pa.as_u64() % page_size == 0 && end - a >= page_size
+            })
+            .unwrap();
 
-    loop {
         let pte = unsafe {
-            walk(page_table, VirtAddr::new(a), true).ok_or(MapToError::FrameAllocationFailed)?
+            walk_level(page_table, VirtAddr::new(a), level, true)
+                .ok_or(MapToError::FrameAllocationFailed)?
         };
         if pte.flags().contains(PageTableEntryFlags::VALID) {
             panic!("map_pages: page table entry already exists");
@@ -136,16 +309,19 @@ fn map_pages(
             PhysAddr::new(pa2pte(pa.as_u64())),
             flags | PageTableEntryFlags::VALID,
         );
-        if a == last {
-            break;
-        }
-        a += PGSIZE;
-        pa += PGSIZE;
+        a += level_size(level);
+        pa += level_size(level);
     }
 
     Ok(())
 }
 
+/// Bytes mapped by a leaf entry at level.
+#[inline]
+const fn level_size(level: u8) -> u64 {
+    PGSIZE << (9 * level as u64)
+}
+
 /// Return the address of the PTE in page table pagetable
 /// that corresponds to virtual address va.  If alloc!=0,
 /// create any required page-table pages.
@@ -158,15 +334,34 @@ fn map_pages(
 ///   21..29 -- 9 bits of level-1 index.
 ///   12..20 -- 9 bits of level-0 index.
 ///    0..11 -- 12 bits of byte offset within the page.
+///
+/// Sv48 adds a fourth level: bits 39..47 are the level-3
+/// index, and only 48..63 must be zero.
 unsafe fn walk(
+    page_table: &mut PageTable,
+    va: VirtAddr,
+    alloc: bool,
+) -> Option<&mut PageTableEntry> {
+    walk_level(page_table, va, 0, alloc)
+}
+
+/// Like walk(), but return the PTE at level instead of level 0,
+/// for mapping megapages. Returns None if va lies within a
+/// megapage mapped above level.
+unsafe fn walk_level(
     mut page_table: &mut PageTable,
     va: VirtAddr,
+    level: u8,
     alloc: bool,
 ) -> Option<&mut PageTableEntry> {
     assert!(va.as_u64() < MAXVA, "walk: va out of range");
 
-    for level in (0..3).rev() {
+    for level in (level + 1..PTLEVELS).rev() {
         let pte = &mut page_table[pg_index(level, va.as_u64()) as usize];
+        if pte.is_leaf() {
+            assert!(!alloc, "walk: va is inside a megapage");
+            return None;
+        }
         if pte.flags().contains(PageTableEntryFlags::VALID) {
             page_table = (pte2pa(pte.as_u64()) as *mut PageTable).as_mut().unwrap();
         } else {
@@ -182,17 +377,145 @@ unsafe fn walk(
         }
     }
 
-    Some(&mut page_table[pg_index(0, va.as_u64()) as usize])
+    Some(&mut page_table[pg_index(level, va.as_u64()) as usize])
 }
 
+/// Allocate a zeroed page-table page from the physical page allocator.
 fn allocate_page_table() -> Option<*mut PageTable> {
-    let ptr = unsafe { alloc::alloc::alloc_zeroed(alloc::alloc::Layout::new::<PageTable>()) };
+    let frame = kalloc(MemTag::PageTable)?;
+    let page_table = frame.into_start_address().as_u64() as *mut PageTable;
+    unsafe {
+        ptr::write_bytes(page_table, 0, 1);
+    }
 
-    if ptr.is_null() {
-        None
-    } else {
-        Some(ptr as *mut PageTable)
+    Some(page_table)
+}
+
+/// Remove npages of mappings starting from va. va must be
+/// page-aligned. The mappings must exist.
+/// Optionally free the physical memory.
+/// Does not flush the TLB.
+pub fn unmap_pages(
+    page_table: &mut PageTable,
+    va: VirtAddr,
+    npages: u64,
+    do_free: bool,
+) -> Result<(), UnmapError> {
+    assert!(va.is_aligned(), "unmap_pages: not aligned");
+
+    for a in (va.as_u64()..va.as_u64() + npages * PGSIZE).step_by(PGSIZE as usize) {
+        let pte = unsafe { walk(page_table, VirtAddr::new(a), false) }
+            .filter(|pte| {
+                pte.flags().contains(PageTableEntryFlags::VALID) || pte.swap_slot().is_some()
+            })
+            .ok_or(UnmapError::PageNotMapped)?;
+        if let Some(slot) = pte.swap_slot() {
+            // swapped out; only the swap slot is left to free.
+            if do_free {
+                swap_free(slot);
+            }
+            pte.clear();
+            continue;
+        }
+        if !pte.is_leaf() {
+            panic!("unmap_pages: not a leaf");
+        }
+        if do_free {
+            kfree(unsafe { PhysFrame::from_start_address(pte.addr()) });
+        }
+        pte.clear();
+    }
+
+    Ok(())
+}
+
+/// Look up a virtual address, return the physical address
+/// it maps to (including the offset within the page) and
+/// the flags of the mapping, or None if it is not mapped.
+pub fn translate(page_table: &PageTable, va: VirtAddr) -> Option<(PhysAddr, PageTableEntryFlags)> {
+    if va.as_u64() >= MAXVA {
+        return None;
     }
+
+    let mut page_table = page_table;
+    for level in (0..PTLEVELS).rev() {
+        let pte = &page_table[pg_index(level, va.as_u64()) as usize];
+        if !pte.flags().contains(PageTableEntryFlags::VALID) {
+            return None;
+        }
+        if pte.is_leaf() || level == 0 {
+            let offset = va.as_u64() & (level_size(level) - 1);
+            return Some((pte.addr() + offset, pte.flags()));
+        }
+        page_table = unsafe { &*(pte.addr().as_u64() as *const PageTable) };
+    }
+
+    unreachable!()
+}
+
+/// Replace the permission flags (R, W, X and U) of every page in
+/// [va, va+size) with those in flags. The rest are kept: the
+/// accessed and dirty bits, and the COW and SWAPPED software bits.
+/// The pages must be mapped. Does not flush the TLB.
+pub fn protect(
+    page_table: &mut PageTable,
+    va: VirtAddr,
+    size: u64,
+    flags: PageTableEntryFlags,
+) -> Result<(), FlagUpdateError> {
+    assert!(size > 0, "protect: size must be > 0");
+    let perms = PageTableEntryFlags::READABLE
+        | PageTableEntryFlags::WRITABLE
+        | PageTableEntryFlags::EXECUTABLE
+        | PageTableEntryFlags::USER;
+
+    let first = pg_round_down(va.as_u64());
+    let last = pg_round_down(va.as_u64() + size - 1);
+
+    for a in (first..=last).step_by(PGSIZE as usize) {
+        let pte = unsafe { walk(page_table, VirtAddr::new(a), false) }
+            .filter(|pte| pte.flags().contains(PageTableEntryFlags::VALID))
+            .ok_or(FlagUpdateError::PageNotMapped)?;
+        let kept = pte.flags() - perms;
+        pte.set_addr(
+            PhysAddr::new(pa2pte(pte.addr().as_u64())),
+            kept | (flags & perms),
+        );
+    }
+
+    Ok(())
+}
+
+/// Recursively free page-table pages, including page_table itself.
+/// Leaf mappings must already have been removed, unless free_leaves
+/// is set, in which case the pages they map are freed too.
+pub unsafe fn free_page_table(page_table: *mut PageTable, free_leaves: bool) {
+    // there are 2^9 = 512 PTEs in a page table.
+    for pte in (*page_table).entries.iter_mut() {
+        if !pte.flags().contains(PageTableEntryFlags::VALID) {
+            if let Some(slot) = pte.swap_slot() {
+                if !free_leaves {
+                    panic!("free_page_table: swapped-out leaf");
+                }
+                swap_free(slot);
+                pte.clear();
+            }
+            continue;
+        }
+        if pte.is_leaf() {
+            if !free_leaves {
+                panic!("free_page_table: leaf");
+            }
+            kfree(PhysFrame::from_start_address(pte.addr()));
+        } else {
+            // this PTE points to a lower-level page table.
+            free_page_table(pte.addr().as_u64() as *mut PageTable, free_leaves);
+        }
+        pte.clear();
+    }
+    kfree(PhysFrame::from_start_address(PhysAddr::new(
+        page_table as u64,
+    )));
 }
 
 #[derive(Debug)]
@@ -200,6 +523,16 @@ pub enum MapToError {
     FrameAllocationFailed,
 }
 
+#[derive(Debug)]
+pub enum UnmapError {
+    PageNotMapped,
+}
+
+#[derive(Debug)]
+pub enum FlagUpdateError {
+    PageNotMapped,
+}
+
 const ENTRY_COUNT: usize = 512;
 
 #[repr(C)]
@@ -214,6 +547,76 @@ impl PageTable {
             entries: [PageTableEntry::new(); ENTRY_COUNT],
         }
     }
+
+    /// Iterate over all leaf mappings, in order of virtual address.
+    pub fn mappings(&self) -> Mappings<'_> {
+        Mappings {
+            tables: [self; PTLEVELS as usize],
+            index: [0; PTLEVELS as usize],
+            level: PTLEVELS as usize - 1,
+        }
+    }
+}
+
+/// A leaf mapping found by [`PageTable::mappings`].
+pub struct Mapping {
+    pub va: VirtAddr,
+    pub pa: PhysAddr,
+    pub size: u64,
+    pub flags: PageTableEntryFlags,
+}
+
+/// Depth-first walk over the leaf entries of a page table.
+pub struct Mappings<'a> {
+    // the table being scanned at each level, and the next index in it.
+    tables: [&'a PageTable; PTLEVELS as usize],
+    index: [usize; PTLEVELS as usize],
+    level: usize,
+}
+
+impl Iterator for Mappings<'_> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        loop {
+            let level = self.level;
+            if self.index[level] == ENTRY_COUNT {
+                // done with this table; resume its parent.
+                if level == PTLEVELS as usize - 1 {
+                    return None;
+                }
+                self.level += 1;
+                continue;
+            }
+
+            let i = self.index[level];
+            self.index[level] += 1;
+            let pte = &self.tables[level][i];
+            if !pte.flags().contains(PageTableEntryFlags::VALID) {
+                continue;
+            }
+
+            if pte.is_leaf() || level == 0 {
+                let mut va = 0;
+                for l in level..PTLEVELS as usize {
+                    // index[l] has already moved past the entry we are in.
+                    let index = if l == level { i } else { self.index[l] - 1 };
+                    va |= (index as u64) << (PGSHIFT + 9 * l as u64);
+                }
+                return Some(Mapping {
+                    va: VirtAddr::new(va),
+                    pa: pte.addr(),
+                    size: PGSIZE << (9 * level),
+                    flags: pte.flags(),
+                });
+            }
+
+            // descend into the next-level table.
+            self.level -= 1;
+            self.tables[self.level] = unsafe { &*(pte.addr().as_u64() as *const PageTable) };
+            self.index[self.level] = 0;
+        }
+    }
 }
 
 impl Index<usize> for PageTable {
@@ -262,6 +665,53 @@ impl PageTableEntry {
     pub fn set_flags(&mut self, flags: PageTableEntryFlags) {
         self.entry |= flags.bits();
     }
+
+    /// The physical address this entry points to.
+    #[inline]
+    pub const fn addr(&self) -> PhysAddr {
+        PhysAddr::new(pte2pa(self.entry))
+    }
+
+    /// A valid entry with any of R, W or X set maps memory;
+    /// otherwise it points to the next-level page table.
+    #[inline]
+    pub fn is_leaf(&self) -> bool {
+        self.flags().intersects(
+            PageTableEntryFlags::READABLE
+                | PageTableEntryFlags::WRITABLE
+                | PageTableEntryFlags::EXECUTABLE,
+        )
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.entry = 0;
+    }
+
+    /// The swap slot of a swapped-out page, which a non-valid
+    /// entry with SWAPPED set keeps where the PPN would be.
+    #[inline]
+    pub fn swap_slot(&self) -> Option<usize> {
+        let flags = self.flags();
+        if !flags.contains(PageTableEntryFlags::VALID)
+            && flags.contains(PageTableEntryFlags::SWAPPED)
+        {
+            Some((self.entry >> 10) as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Mark the page swapped out to slot, remembering its
+    /// permissions for when it is swapped back in.
+    #[inline]
+    pub fn set_swapped(&mut self, slot: usize, flags: PageTableEntryFlags) {
+        let flags = flags
+            - PageTableEntryFlags::VALID
+            - PageTableEntryFlags::ACCESSED
+            - PageTableEntryFlags::DIRTY;
+        self.entry = (slot as u64) << 10 | (flags | PageTableEntryFlags::SWAPPED).bits();
+    }
 }
 
 struct PageTablePtr(UnsafeCell<NonNull<PageTable>>);
@@ -291,10 +741,23 @@ bitflags! {
         const EXECUTABLE = 1 << 3;
         /// User can access the page.
         const USER = 1 << 4;
+        /// Mapping exists in all address spaces.
+        const GLOBAL = 1 << 5;
+        /// Set by the hardware when the page is read, written or fetched.
+        const ACCESSED = 1 << 6;
+        /// Set by the hardware when the page is written.
+        const DIRTY = 1 << 7;
+        /// Software-defined (RSW): a page shared copy-on-write
+        /// that was writable before it was shared.
+        const COW = 1 << 8;
+        /// Software-defined (RSW), in non-valid entries only: the
+        /// page is swapped out, to the slot in the PPN field.
+        const SWAPPED = 1 << 9;
     }
 }
 
 #[repr(transparent)]
+#[derive(Copy, Clone)]
 pub struct VirtAddr(u64);
 
 #[repr(transparent)]
@@ -344,10 +807,840 @@ impl VirtAddr {
     pub const fn as_u64(&self) -> u64 {
         self.0
     }
+
+    #[inline]
+    pub const fn is_aligned(&self) -> bool {
+        pg_round_down(self.0) == self.0
+    }
+
+    /// Offset of the address within its page.
+    #[inline]
+    pub const fn page_offset(&self) -> u64 {
+        self.0 & (PGSIZE - 1)
+    }
 }
 /// Switch h/w page table register to the kernel's page table,
 /// and enable paging.
 pub fn kvminithart() {
-    w_satp(make_satp(KERNEL_PAGE_TABLE.as_u64()));
+    // find out how many ASID bits this hart implements:
+    // the ones it lacks read back as zero.
+    w_satp(make_satp(KERNEL_PAGE_TABLE.as_u64(), SATP_ASID_MASK as u16));
+    let bits = (r_satp() as u64 >> SATP_ASID_SHIFT & SATP_ASID_MASK).count_ones();
+    ASID_BITS.fetch_min(bits as usize, Ordering::Relaxed);
+
+    // the kernel runs with ASID 0.
+    w_satp(make_satp(KERNEL_PAGE_TABLE.as_u64(), 0));
     sfence_vma();
 }
+
+/// Number of ASID bits the harts implement. With none, every
+/// address space runs with ASID 0 and the whole TLB is flushed
+/// on each switch.
+static ASID_BITS: AtomicUsize = AtomicUsize::new(16);
+
+/// Hands out ASIDs to user address spaces; ASID 0 is the kernel's.
+/// When they run out a new generation begins: each hart flushes its
+/// whole TLB before its next switch, and address spaces holding an
+/// ASID from an older generation get a new one when next switched to.
+static ASIDS: SpinMutex<AsidAllocator> = SpinMutex::new(
+    "asid",
+    AsidAllocator {
+        generation: 1,
+        next: 1,
+    },
+);
+
+struct AsidAllocator {
+    generation: usize,
+    next: usize,
+}
+
+/// A user address space.
+///
+/// Owns a user page table with the trampoline and the process's
+/// trapframe mapped at the top of the address space, and user memory
+/// from virtual address zero up to the process size. Dropping it frees
+/// the user memory and every page-table page, but not the trapframe,
+/// which belongs to the process.
+pub struct AddressSpace {
+    page_table: NonNull<PageTable>,
+    // user memory below heap_end that is not mapped yet is
+    // allocated and zeroed on first touch, see handle_fault().
+    heap_end: u64,
+    // regions created by mmap(), growing down from TRAPFRAME.
+    vmas: Vec<Vma>,
+    // ASID and the generation it was handed out in; see activate().
+    asid: u16,
+    asid_generation: usize,
+    // the hart this address space was last switched to on.
+    hart: Option<usize>,
+    // clock hand of swap_out(): the next page it looks at.
+    swap_hand: u64,
+}
+
+impl AddressSpace {
+    /// Create a user page table with no user memory,
+    /// but with the trampoline and trapframe pages.
+    pub fn new(trapframe: PhysAddr) -> Result<AddressSpace, MapToError> {
+        let page_table = allocate_page_table().ok_or(MapToError::FrameAllocationFailed)?;
+        let mut space = AddressSpace {
+            page_table: NonNull::new(page_table).unwrap(),
+            heap_end: 0,
+            vmas: Vec::new(),
+            asid: 0,
+            asid_generation: 0,
+            hart: None,
+            swap_hand: 0,
+        };
+
+        // map the trampoline code (for system call return)
+        // at the highest user virtual address.
+        // only the supervisor uses it, on the way
+        // to/from user space, so not USER.
+        map_pages(
+            space.page_table_mut(),
+            VirtAddr::new(TRAMPOLINE),
+            PhysAddr::new(unsafe { ptr::addr_of!(trampoline) } as u64),
+            PGSIZE,
+            PageTableEntryFlags::READABLE | PageTableEntryFlags::EXECUTABLE,
+        )?;
+
+        // map the trapframe page just below the trampoline page, for
+        // trampoline.S.
+        map_pages(
+            space.page_table_mut(),
+            VirtAddr::new(TRAPFRAME),
+            trapframe,
+            PGSIZE,
+            PageTableEntryFlags::READABLE | PageTableEntryFlags::WRITABLE,
+        )?;
+
+        Ok(space)
+    }
+
+    #[inline]
+    pub fn page_table(&self) -> &PageTable {
+        unsafe { self.page_table.as_ref() }
+    }
+
+    #[inline]
+    pub fn page_table_mut(&mut self) -> &mut PageTable {
+        unsafe { self.page_table.as_mut() }
+    }
+
+    /// Let the heap grow lazily up to end: the pages between the
+    /// current size and end get memory only when first used.
+    #[inline]
+    pub fn set_heap_end(&mut self, end: u64) {
+        self.heap_end = end;
+    }
+
+    /// The lowest address used by mmap() regions; the heap
+    /// must not grow past it.
+    pub fn mmap_base(&self) -> u64 {
+        self.vmas
+            .iter()
+            .map(|vma| vma.start)
+            .min()
+            .unwrap_or(TRAPFRAME)
+    }
+
+    /// Get ready to switch to this address space on this hart,
+    /// and return the satp value that does it. Must be called with
+    /// interrupts disabled.
+    ///
+    /// With ASIDs the TLB keeps entries of other address spaces
+    /// across switches, so only entries that may be stale are
+    /// flushed here. Without, satp gets ASID 0 and trampoline.S
+    /// flushes the whole TLB on every switch.
+    pub fn activate(&mut self) -> u64 {
+        let max = (1 << ASID_BITS.load(Ordering::Relaxed)) - 1;
+        if max == 0 {
+            return make_satp(self.page_table.as_ptr() as u64, 0);
+        }
+
+        let mut asids = ASIDS.lock();
+        if self.asid_generation != asids.generation {
+            if asids.next > max {
+                // out of ASIDs; start over in a new generation.
+                asids.generation += 1;
+                asids.next = 1;
+            }
+            self.asid = asids.next as u16;
+            self.asid_generation = asids.generation;
+            asids.next += 1;
+        }
+
+        let cpu = CPUS.mycpu();
+        if cpu.asid_generation != asids.generation {
+            // this hart's TLB may hold entries under ASIDs
+            // that have since been handed out again.
+            sfence_vma();
+            cpu.asid_generation = asids.generation;
+        } else if self.hart != Some(cpuid()) {
+            // changes made while we ran elsewhere were only
+            // flushed there; entries left here may be stale.
+            sfence_vma_asid(self.asid);
+        }
+        self.hart = Some(cpuid());
+
+        make_satp(self.page_table.as_ptr() as u64, self.asid)
+    }
+
+    /// Flush the TLB entry for the page at va, after its PTE
+    /// was changed.
+    fn flush_page(&mut self, va: VirtAddr) {
+        sfence_vma_page(va.as_u64(), self.asid);
+        self.forget_hart();
+    }
+
+    /// Flush all of this address space's TLB entries.
+    fn flush(&mut self) {
+        sfence_vma_asid(self.asid);
+        self.forget_hart();
+    }
+
+    // the flushes only reach this hart. if we last ran on another,
+    // make activate() flush there too before we run there again.
+    fn forget_hart(&mut self) {
+        if self.hart != Some(cpuid()) {
+            self.hart = None;
+        }
+    }
+
+    /// Allocate PTEs and physical memory to grow process from oldsz to
+    /// newsz, which need not be page aligned. Returns new size.
+    pub fn alloc(
+        &mut self,
+        oldsz: u64,
+        newsz: u64,
+        xperm: PageTableEntryFlags,
+    ) -> Result<u64, MapToError> {
+        if newsz < oldsz {
+            return Ok(oldsz);
+        }
+
+        let oldsz = pg_round_up(oldsz);
+        for a in (oldsz..newsz).step_by(PGSIZE as usize) {
+            let frame = match self.alloc_user_page() {
+                Some(frame) => frame,
+                None => {
+                    self.dealloc(a, oldsz);
+                    return Err(MapToError::FrameAllocationFailed);
+                }
+            };
+            unsafe {
+                ptr::write_bytes(frame.as_mut_ptr::<u8>(), 0, PGSIZE as usize);
+            }
+            let pa = frame.into_start_address();
+            if let Err(err) = map_pages(
+                self.page_table_mut(),
+                VirtAddr::new(a),
+                pa,
+                PGSIZE,
+                PageTableEntryFlags::READABLE | PageTableEntryFlags::USER | xperm,
+            ) {
+                kfree(unsafe { PhysFrame::from_start_address(pa) });
+                self.dealloc(a, oldsz);
+                return Err(err);
+            }
+        }
+        self.heap_end = self.heap_end.max(newsz);
+
+        Ok(newsz)
+    }
+
+    /// Deallocate user pages to bring the process size from oldsz to
+    /// newsz. oldsz and newsz need not be page-aligned, nor does newsz
+    /// need to be less than oldsz. oldsz can be larger than the actual
+    /// process size. Returns the new process size.
+    pub fn dealloc(&mut self, oldsz: u64, newsz: u64) -> u64 {
+        if newsz >= oldsz {
+            return oldsz;
+        }
+
+        // pages of a lazily grown heap may never have been touched.
+        for a in (pg_round_up(newsz)..pg_round_up(oldsz)).step_by(PGSIZE as usize) {
+            match unmap_pages(self.page_table_mut(), VirtAddr::new(a), 1, true) {
+                Ok(()) | Err(UnmapError::PageNotMapped) => {}
+            }
+        }
+        self.heap_end = self.heap_end.min(newsz);
+        self.flush();
+
+        newsz
+    }
+
+    /// Create a copy of this address space for a child process:
+    /// a fresh page table mapping the child's trapframe, sharing the
+    /// first sz bytes of user memory and the mmap regions with this
+    /// one. Writable pages become copy-on-write in both, and are only
+    /// copied by handle_fault() when one side stores to them; pages
+    /// of MAP_SHARED regions stay shared.
+    pub fn copy(&mut self, trapframe: PhysAddr, sz: u64) -> Result<AddressSpace, MapToError> {
+        // if anything goes wrong, dropping child frees what was mapped.
+        let mut child = AddressSpace::new(trapframe)?;
+        child.heap_end = self.heap_end;
+        let mut regions = Vec::new();
+        child
+            .vmas
+            .try_reserve_exact(self.vmas.len())
+            .and_then(|()| regions.try_reserve_exact(self.vmas.len()))
+            .map_err(|_| MapToError::FrameAllocationFailed)?;
+        child.vmas.extend(self.vmas.iter().cloned());
+        regions.extend(self.vmas.iter().map(|vma| (vma.start, vma.end)));
+
+        let heap = (0..sz).step_by(PGSIZE as usize);
+        let regions = regions
+            .into_iter()
+            .flat_map(|(start, end)| (start..end).step_by(PGSIZE as usize));
+
+        for va in heap.chain(regions) {
+            let va = VirtAddr::new(va);
+            let shared = self
+                .vmas
+                .iter()
+                .find(|vma| vma.contains(va.as_u64()) && vma.is_shared())
+                .cloned();
+            if let Some(vma) = &shared {
+                if translate(self.page_table(), va).is_none() && vma.prot != PROT_NONE {
+                    // both sides must end up with the same page, so it
+                    // can't be left for each to fault in on its own.
+                    self.fault_in_vma(va, vma)
+                        .map_err(|_| MapToError::FrameAllocationFailed)?;
+                }
+            }
+            let swapped = unsafe { walk(self.page_table_mut(), va, false) }
+                .and_then(|pte| Some((pte.swap_slot()?, pte.flags())));
+            if let Some((slot, flags)) = swapped {
+                // the child can't share a page that isn't there.
+                self.swap_in(va, slot, flags)
+                    .map_err(|_| MapToError::FrameAllocationFailed)?;
+            }
+            let pte = match unsafe { walk(self.page_table_mut(), va, false) } {
+                Some(pte) if pte.flags().contains(PageTableEntryFlags::VALID) => pte,
+                // not touched yet; the child will fault it in itself.
+                _ => continue,
+            };
+
+            let pa = pte.addr();
+            let mut flags = pte.flags();
+            if shared.is_none() && flags.contains(PageTableEntryFlags::WRITABLE) {
+                flags.remove(PageTableEntryFlags::WRITABLE);
+                flags.insert(PageTableEntryFlags::COW);
+                pte.set_addr(PhysAddr::new(pa2pte(pa.as_u64())), flags);
+            }
+
+            map_pages(child.page_table_mut(), va, pa, PGSIZE, flags)?;
+            page_get(pa);
+        }
+
+        // our own writable mappings just became read-only.
+        self.flush();
+
+        Ok(child)
+    }
+
+    /// Report which of the npages pages starting at va were accessed
+    /// since the last call, as a bitmask with bit i for page i, and
+    /// clear their accessed bits. Pages not mapped count as unused.
+    pub fn pgaccess(&mut self, va: VirtAddr, npages: u64) -> Result<Vec<u8>, CopyError> {
+        let end = npages
+            .checked_mul(PGSIZE)
+            .and_then(|len| va.as_u64().checked_add(len))
+            .filter(|&end| va.is_aligned() && end <= MAXVA)
+            .ok_or(CopyError::BadAddress)?;
+
+        let mut mask = vec![0u8; (npages as usize + 7) / 8];
+        for (i, a) in (va.as_u64()..end).step_by(PGSIZE as usize).enumerate() {
+            let pte = match unsafe { walk(self.page_table_mut(), VirtAddr::new(a), false) } {
+                Some(pte) if pte.flags().contains(PageTableEntryFlags::VALID) => pte,
+                _ => continue,
+            };
+            if !pte.flags().contains(PageTableEntryFlags::USER) {
+                return Err(CopyError::BadAddress);
+            }
+            if pte.flags().contains(PageTableEntryFlags::ACCESSED) {
+                mask[i / 8] |= 1 << (i % 8);
+                let flags = pte.flags() - PageTableEntryFlags::ACCESSED;
+                pte.set_addr(PhysAddr::new(pa2pte(pte.addr().as_u64())), flags);
+            }
+        }
+        // or the TLB would keep the accessed pages accessed.
+        self.flush();
+
+        Ok(mask)
+    }
+
+    /// Try to resolve a page fault at va in this address space, for a
+    /// store if write is set. Fails if the access is simply not
+    /// allowed, in which case the faulting process should be killed.
+    pub fn handle_fault(&mut self, va: VirtAddr, write: bool) -> Result<(), FaultError> {
+        if va.as_u64() >= MAXVA {
+            return Err(FaultError::BadAddress);
+        }
+
+        let va0 = VirtAddr::new(pg_round_down(va.as_u64()));
+        let lazy = va.as_u64() < self.heap_end;
+        let vma = self.vmas.iter().position(|vma| vma.contains(va.as_u64()));
+        let pte = match unsafe { walk(self.page_table_mut(), va0, false) } {
+            Some(pte) if pte.flags().contains(PageTableEntryFlags::VALID) => pte,
+            Some(pte) if pte.swap_slot().is_some() => {
+                let (slot, flags) = (pte.swap_slot().unwrap(), pte.flags());
+                return self.swap_in(va0, slot, flags);
+            }
+            _ if lazy => {
+                let flags = PageTableEntryFlags::READABLE
+                    | PageTableEntryFlags::WRITABLE
+                    | PageTableEntryFlags::USER;
+                return self.fault_in(va0, flags);
+            }
+            _ => match vma {
+                Some(i) if self.vmas[i].allows(write) => {
+                    let vma = self.vmas[i].clone();
+                    return self.fault_in_vma(va0, &vma);
+                }
+                _ => return Err(FaultError::BadAddress),
+            },
+        };
+
+        // a mapped page without USER is never accessible.
+        let flags = pte.flags();
+        if !write || !flags.contains(PageTableEntryFlags::USER | PageTableEntryFlags::COW) {
+            return Err(FaultError::BadAddress);
+        }
+
+        // a store to a copy-on-write page.
+        let pa = pte.addr();
+        let flags = (flags - PageTableEntryFlags::COW) | PageTableEntryFlags::WRITABLE;
+        if page_refs(pa) == 1 {
+            // everyone else sharing the page has let go of it.
+            pte.set_addr(PhysAddr::new(pa2pte(pa.as_u64())), flags);
+        } else {
+            let frame = self.alloc_user_page().ok_or(FaultError::OutOfMemory)?;
+            // making room may have swapped out this very page, if the
+            // others sharing it let go meanwhile. then just retry.
+            let pte = match unsafe { walk(self.page_table_mut(), va0, false) } {
+                Some(pte)
+                    if pte.flags().contains(PageTableEntryFlags::VALID)
+                        && pte.addr().as_u64() == pa.as_u64() =>
+                {
+                    pte
+                }
+                _ => {
+                    kfree(frame);
+                    return Ok(());
+                }
+            };
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    pa.as_u64() as *const u8,
+                    frame.as_mut_ptr::<u8>(),
+                    PGSIZE as usize,
+                );
+            }
+            pte.set_addr(
+                PhysAddr::new(pa2pte(frame.into_start_address().as_u64())),
+                flags,
+            );
+            kfree(unsafe { PhysFrame::from_start_address(pa) });
+        }
+        self.flush_page(va0);
+
+        Ok(())
+    }
+}
+
+impl AddressSpace {
+    /// Allocate a page of user memory, swapping out one of ours
+    /// to make room if there is none.
+    fn alloc_user_page(&mut self) -> Option<PhysFrame> {
+        loop {
+            if let Some(frame) = kalloc(MemTag::User) {
+                return Some(frame);
+            }
+            if !PROCS.swap_out(self) {
+                return None;
+            }
+        }
+    }
+
+    /// The lowest page at or above va that swap_victim() may choose:
+    /// heap pages, then pages of private mmap regions.
+    fn swappable_from(&self, va: u64) -> Option<u64> {
+        if va < pg_round_up(self.heap_end) {
+            return Some(va);
+        }
+        self.vmas
+            .iter()
+            .filter(|vma| !vma.is_shared() && va < vma.end)
+            .map(|vma| vma.start.max(va))
+            .min()
+    }
+
+    /// Choose one of our pages to swap out, and leave a PTE for a
+    /// newly allocated swap slot in its place. Returns the slot and
+    /// the page, which the caller writes out with swap_write() and
+    /// then frees. Returns None if there is nothing to swap, or
+    /// nowhere to.
+    ///
+    /// Victims are picked by the clock algorithm: the hand sweeps
+    /// over the pages, and a page that was accessed since the hand
+    /// last passed gets a second chance, with its accessed bit
+    /// cleared. Pages shared with another address space stay put.
+    pub fn swap_victim(&mut self) -> Option<(usize, PhysFrame)> {
+        if !swap_enabled() {
+            return None;
+        }
+
+        // two sweeps: the first may only clear accessed bits.
+        let npages = (pg_round_up(self.heap_end)
+            + self
+                .vmas
+                .iter()
+                .filter(|vma| !vma.is_shared())
+                .map(|vma| vma.end - vma.start)
+                .sum::<u64>())
+            / PGSIZE;
+        for _ in 0..2 * npages {
+            let va = match self.swappable_from(self.swap_hand) {
+                Some(va) => va,
+                // wrap around.
+                None => self.swappable_from(0)?,
+            };
+            self.swap_hand = va + PGSIZE;
+
+            let va = VirtAddr::new(va);
+            let pte = match unsafe { walk(self.page_table_mut(), va, false) } {
+                Some(pte) if pte.flags().contains(PageTableEntryFlags::VALID) => pte,
+                _ => continue,
+            };
+            let pa = pte.addr();
+            let flags = pte.flags();
+            if !flags.contains(PageTableEntryFlags::USER) || page_refs(pa) != 1 {
+                continue;
+            }
+            if flags.contains(PageTableEntryFlags::ACCESSED) {
+                // second chance.
+                pte.set_addr(
+                    PhysAddr::new(pa2pte(pa.as_u64())),
+                    flags - PageTableEntryFlags::ACCESSED,
+                );
+                self.flush_page(va);
+                continue;
+            }
+
+            let slot = swap_alloc()?;
+            pte.set_swapped(slot, flags);
+            self.flush_page(va);
+            return Some((slot, unsafe { PhysFrame::from_start_address(pa) }));
+        }
+
+        None
+    }
+
+    /// Read the page at va0 back in from its swap slot.
+    fn swap_in(
+        &mut self,
+        va0: VirtAddr,
+        slot: usize,
+        flags: PageTableEntryFlags,
+    ) -> Result<(), FaultError> {
+        let frame = self.alloc_user_page().ok_or(FaultError::OutOfMemory)?;
+        swap_read(slot, frame.start_address());
+
+        // a swapped-out page is never chosen again, and no one
+        // takes pages from us while we use them, so its PTE is
+        // still waiting for it.
+        let pte = unsafe { walk(self.page_table_mut(), va0, false) }.unwrap();
+        pte.set_addr(
+            PhysAddr::new(pa2pte(frame.into_start_address().as_u64())),
+            (flags - PageTableEntryFlags::SWAPPED) | PageTableEntryFlags::VALID,
+        );
+        swap_free(slot);
+        self.flush_page(va0);
+
+        Ok(())
+    }
+
+    /// Map a fresh zeroed page at the untouched address va0.
+    fn fault_in(&mut self, va0: VirtAddr, flags: PageTableEntryFlags) -> Result<(), FaultError> {
+        let frame = self.alloc_user_page().ok_or(FaultError::OutOfMemory)?;
+        unsafe {
+            ptr::write_bytes(frame.as_mut_ptr::<u8>(), 0, PGSIZE as usize);
+        }
+        let pa = frame.into_start_address();
+        // mapping may need a page-table page; make room for one.
+        while map_pages(self.page_table_mut(), va0, pa, PGSIZE, flags).is_err() {
+            if !PROCS.swap_out(self) {
+                kfree(unsafe { PhysFrame::from_start_address(pa) });
+                return Err(FaultError::OutOfMemory);
+            }
+        }
+        // the hart may have cached the invalid entry.
+        self.flush_page(va0);
+
+        Ok(())
+    }
+
+    /// Map the page of vma at the untouched address va0: the
+    /// segment's page for a shared memory region, or a fresh
+    /// zeroed one.
+    fn fault_in_vma(&mut self, va0: VirtAddr, vma: &Vma) -> Result<(), FaultError> {
+        let pa = match vma.segment_page(va0.as_u64()) {
+            Some(pa) => pa,
+            None => return self.fault_in(va0, vma.pte_flags()),
+        };
+
+        // the mapping holds its own reference to the page.
+        page_get(pa);
+        while map_pages(self.page_table_mut(), va0, pa, PGSIZE, vma.pte_flags()).is_err() {
+            if !PROCS.swap_out(self) {
+                kfree(unsafe { PhysFrame::from_start_address(pa) });
+                return Err(FaultError::OutOfMemory);
+            }
+        }
+        self.flush_page(va0);
+
+        Ok(())
+    }
+
+    /// Create a region of len bytes below the existing ones, with
+    /// pages filled in on demand from backing. Returns its address.
+    pub fn mmap(
+        &mut self,
+        len: u64,
+        prot: i32,
+        flags: i32,
+        backing: Backing,
+    ) -> Result<u64, MmapError> {
+        if len == 0 || flags & (MAP_SHARED | MAP_PRIVATE) == 0 {
+            return Err(MmapError::InvalidArgument);
+        }
+        if flags & MAP_ANONYMOUS == 0 && matches!(backing, Backing::Anonymous) {
+            return Err(MmapError::Unsupported);
+        }
+        if len > TRAPFRAME {
+            return Err(MmapError::NoSpace);
+        }
+
+        let len = pg_round_up(len);
+        let end = self.mmap_base();
+        let start = end
+            .checked_sub(len)
+            .filter(|&start| start >= pg_round_up(self.heap_end))
+            .ok_or(MmapError::NoSpace)?;
+
+        self.vmas
+            .try_reserve(1)
+            .map_err(|_| MmapError::OutOfMemory)?;
+        self.vmas.push(Vma {
+            start,
+            end,
+            prot,
+            flags,
+            backing,
+        });
+
+        Ok(start)
+    }
+
+    /// Remove the mappings of [addr, addr+len). Regions may be
+    /// trimmed or split.
+    pub fn munmap(&mut self, addr: u64, len: u64) -> Result<(), MmapError> {
+        let end = match addr.checked_add(len) {
+            Some(end) if addr % PGSIZE == 0 && len != 0 && end <= TRAPFRAME => pg_round_up(end),
+            _ => return Err(MmapError::InvalidArgument),
+        };
+
+        // one region may be split in two.
+        let mut kept = Vec::new();
+        kept.try_reserve_exact(self.vmas.len() + 1)
+            .map_err(|_| MmapError::OutOfMemory)?;
+        for vma in core::mem::take(&mut self.vmas) {
+            if vma.end <= addr || end <= vma.start {
+                kept.push(vma);
+                continue;
+            }
+
+            let lo = vma.start.max(addr);
+            let hi = vma.end.min(end);
+            for va in (lo..hi).step_by(PGSIZE as usize) {
+                match unmap_pages(self.page_table_mut(), VirtAddr::new(va), 1, true) {
+                    Ok(()) | Err(UnmapError::PageNotMapped) => {}
+                }
+            }
+
+            if vma.start < lo {
+                kept.push(vma.slice(vma.start, lo));
+            }
+            if hi < vma.end {
+                kept.push(vma.slice(hi, vma.end));
+            }
+        }
+        self.vmas = kept;
+        self.flush();
+
+        Ok(())
+    }
+
+    /// Map a shared memory segment, readable and writable, below the
+    /// existing regions. Returns its address.
+    pub fn shmat(&mut self, segment: Arc<Segment>) -> Result<u64, MmapError> {
+        let len = segment.size();
+        let backing = Backing::Shm { segment, offset: 0 };
+        self.mmap(len, PROT_READ | PROT_WRITE, MAP_SHARED, backing)
+    }
+
+    /// Unmap the shared memory segment mapped at addr by shmat().
+    pub fn shmdt(&mut self, addr: u64) -> Result<(), MmapError> {
+        let vma = self
+            .vmas
+            .iter()
+            .find(|vma| vma.start == addr && matches!(vma.backing, Backing::Shm { .. }))
+            .ok_or(MmapError::InvalidArgument)?;
+        let len = vma.end - vma.start;
+        self.munmap(addr, len)
+    }
+}
+
+#[derive(Debug)]
+pub enum FaultError {
+    /// The address is not mapped, or not for this kind of access.
+    BadAddress,
+    OutOfMemory,
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let page_table = self.page_table_mut();
+        unmap_pages(page_table, VirtAddr::new(TRAMPOLINE), 1, false).unwrap();
+        unmap_pages(page_table, VirtAddr::new(TRAPFRAME), 1, false).unwrap();
+
+        // everything left is user memory.
+        unsafe {
+            free_page_table(self.page_table.as_ptr(), true);
+        }
+    }
+}
+
+impl AddressSpace {
+    /// Copy from kernel to user.
+    /// Copy src to virtual address dstva in this address space.
+    pub fn copy_out(&mut self, mut dstva: VirtAddr, mut src: &[u8]) -> Result<(), CopyError> {
+        while !src.is_empty() {
+            let va0 = pg_round_down(dstva.as_u64());
+            let pa0 = self.user_page(VirtAddr::new(va0), true)?;
+            let offset = dstva.as_u64() - va0;
+            let n = src.len().min((PGSIZE - offset) as usize);
+            unsafe {
+                ptr::copy(src.as_ptr(), (pa0.as_u64() + offset) as *mut u8, n);
+            }
+
+            src = &src[n..];
+            dstva = VirtAddr::new(va0 + PGSIZE);
+        }
+
+        Ok(())
+    }
+
+    /// Copy from user to kernel.
+    /// Fill dst from virtual address srcva in this address space.
+    pub fn copy_in(&mut self, mut dst: &mut [u8], mut srcva: VirtAddr) -> Result<(), CopyError> {
+        while !dst.is_empty() {
+            let va0 = pg_round_down(srcva.as_u64());
+            let pa0 = self.user_page(VirtAddr::new(va0), false)?;
+            let offset = srcva.as_u64() - va0;
+            let n = dst.len().min((PGSIZE - offset) as usize);
+            unsafe {
+                ptr::copy((pa0.as_u64() + offset) as *const u8, dst.as_mut_ptr(), n);
+            }
+
+            dst = &mut dst[n..];
+            srcva = VirtAddr::new(va0 + PGSIZE);
+        }
+
+        Ok(())
+    }
+
+    /// Copy a null-terminated string from user to kernel.
+    /// Copy bytes to dst from virtual address srcva in this address
+    /// space, until a '\0', or dst is full. Returns the length of the
+    /// string, not counting the '\0'.
+    pub fn copy_in_str(&mut self, dst: &mut [u8], mut srcva: VirtAddr) -> Result<usize, CopyError> {
+        let mut n = 0;
+
+        while n < dst.len() {
+            let va0 = pg_round_down(srcva.as_u64());
+            let pa0 = self.user_page(VirtAddr::new(va0), false)?;
+            let offset = srcva.as_u64() - va0;
+            let page = unsafe {
+                core::slice::from_raw_parts(
+                    (pa0.as_u64() + offset) as *const u8,
+                    (PGSIZE - offset) as usize,
+                )
+            };
+
+            for &c in page {
+                if n == dst.len() {
+                    break;
+                }
+                if c == 0 {
+                    return Ok(n);
+                }
+                dst[n] = c;
+                n += 1;
+            }
+
+            srcva = VirtAddr::new(va0 + PGSIZE);
+        }
+
+        Err(CopyError::TooLong)
+    }
+
+    /// Look up the page at page-aligned va for the kernel to access on
+    /// the user's behalf. The page must be mapped with USER set, and
+    /// also WRITABLE if the kernel is going to store to it.
+    fn user_page(&mut self, va: VirtAddr, write: bool) -> Result<PhysAddr, CopyError> {
+        if va.as_u64() >= MAXVA {
+            return Err(CopyError::BadAddress);
+        }
+
+        // fault the page in as the hardware would have: perhaps part
+        // of the heap or a mapped region that hasn't been touched yet,
+        // a page that was swapped out, or a copy-on-write page about
+        // to be written. until it sticks, since making room for one
+        // page may swap out another.
+        let (pa, flags) = loop {
+            match translate(self.page_table(), va) {
+                Some((pa, flags)) if !(write && flags.contains(PageTableEntryFlags::COW)) => {
+                    break (pa, flags)
+                }
+                _ => self.handle_fault(va, write).map_err(|err| match err {
+                    FaultError::BadAddress => CopyError::BadAddress,
+                    FaultError::OutOfMemory => CopyError::OutOfMemory,
+                })?,
+            }
+        };
+
+        let mut required = PageTableEntryFlags::USER | PageTableEntryFlags::READABLE;
+        if write {
+            required |= PageTableEntryFlags::WRITABLE;
+        }
+        if !flags.contains(required) {
+            return Err(CopyError::BadAddress);
+        }
+
+        Ok(pa)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CopyError {
+    /// Part of the user range is not mapped, or not accessible.
+    BadAddress,
+    /// A string did not fit in the kernel buffer.
+    TooLong,
+    /// No memory to fault a page of the range in with.
+    OutOfMemory,
+}